@@ -1,7 +1,8 @@
-use io_queue_rdma::IoQueue;
+use io_queue_rdma::{BufferExt, IoQueue};
 use nix::sys::socket::{InetAddr, SockAddr};
 use std::net::SocketAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use structopt::StructOpt;
 use tracing_subscriber::EnvFilter;
 
@@ -37,6 +38,12 @@ struct Opt {
     port: String,
 }
 
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigint(_signal: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
 fn main() {
     tracing_subscriber::fmt::Subscriber::builder()
         .with_env_filter(EnvFilter::from_default_env())
@@ -61,27 +68,55 @@ fn main() {
                 )
                 .unwrap();
             io_queue.listen(&mut listening_qd);
+            // Installed before `accept` so there is no window in which Ctrl-C kills us with the
+            // client connected. `accept` blocks inside rdma-cm, so a Ctrl-C while waiting there
+            // is only acted on once a client shows up.
+            let handler: extern "C" fn(libc::c_int) = on_sigint;
+            unsafe { libc::signal(libc::SIGINT, handler as libc::sighandler_t) };
             let mut connected_qd = io_queue.accept(&mut listening_qd);
             println!("Connected to client!");
 
             println!("Waiting to receive byte...");
             let qt = io_queue.pop(&mut connected_qd);
-            let mut buffer = io_queue.wait(qt).pop_op();
+            let buffer = loop {
+                if INTERRUPTED.load(Ordering::SeqCst) {
+                    println!("Interrupted, shutting down.");
+                    // Disconnects the client and tears everything down.
+                    io_queue.shutdown();
+                    return;
+                }
+                if let Some(completed) = io_queue.try_wait(qt) {
+                    break completed.pop_op();
+                }
+            };
 
-            println!("Server got: {:?}", buffer.as_mut_slice(1)[0]);
+            println!(
+                "Server got {} byte(s): {:?}",
+                buffer.len(),
+                buffer.as_slice()
+            );
+            io_queue
+                .free(&mut connected_qd, buffer)
+                .expect("Buffer freed twice.");
         }
         Mode::Client => {
             let mut io_queue = IoQueue::<2048, 256, 32, 1024, 1024>::new();
             let mut connection = io_queue.socket();
-            io_queue.connect(&mut connection, &opt.ip_address, &opt.port);
+            io_queue
+                .connect(&mut connection, &opt.ip_address, &opt.port)
+                .expect("Unable to connect.");
 
             println!("Sending byte to server.");
             let mut memory = io_queue.malloc(&mut connection);
-            memory.as_mut_slice(1)[0] = 42;
-            let qt = io_queue.push(&mut connection, memory);
+            memory.checked_mut_slice(1).expect("Buffer too small.")[0] = 42;
+            let qt = io_queue
+                .push(&mut connection, memory)
+                .expect("Push queue full.");
             // Acquire our allocated memory again.
             let memory = io_queue.wait(qt).push_op();
-            io_queue.free(&mut connection, memory);
+            io_queue
+                .free(&mut connection, memory)
+                .expect("Buffer freed twice.");
             println!("Byte sent!");
         }
     }
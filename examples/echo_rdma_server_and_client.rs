@@ -80,10 +80,14 @@ fn main() {
                 let qt = io_queue.pop(&mut connected_qd);
                 let memory = io_queue.wait(qt).pop_op();
 
-                let qt = io_queue.push(&mut connected_qd, memory);
+                let qt = io_queue
+                    .push(&mut connected_qd, memory)
+                    .expect("Push queue full.");
                 let memory = io_queue.wait(qt).push_op();
 
-                io_queue.free(&mut connected_qd, memory);
+                io_queue
+                    .free(&mut connected_qd, memory)
+                    .expect("Buffer freed twice.");
             }
 
             io_queue.disconnect(connected_qd);
@@ -92,7 +96,9 @@ fn main() {
             let mut io_queue =
                 IoQueue::<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>::new();
             let mut connection = io_queue.socket();
-            io_queue.connect(&mut connection, &opt.ip_address, &opt.port);
+            io_queue
+                .connect(&mut connection, &opt.ip_address, &opt.port)
+                .expect("Unable to connect.");
 
             println!("Client connected");
 
@@ -113,7 +119,9 @@ fn main() {
                 let roundtrip_time = Instant::now();
 
                 let push_time = Instant::now();
-                let qt = io_queue.push(&mut connection, memory);
+                let qt = io_queue
+                    .push(&mut connection, memory)
+                    .expect("Push queue full.");
                 push += push_time.elapsed().as_micros() as u32;
 
                 let push_wait_time = Instant::now();
@@ -135,8 +143,12 @@ fn main() {
                     assert_eq!(slice[i], (loop_val % 255) as u8);
                 }
 
-                io_queue.free(&mut connection, memory1);
-                io_queue.free(&mut connection, memory2);
+                io_queue
+                    .free(&mut connection, memory1)
+                    .expect("Buffer freed twice.");
+                io_queue
+                    .free(&mut connection, memory2)
+                    .expect("Buffer freed twice.");
             }
 
             let loops = opt.loops as f64;
@@ -138,9 +138,12 @@ impl<
             qtokens.swap_remove(i);
 
             match result {
-                CompletedRequest::Pop(memory) => {
+                CompletedRequest::Pop(memory, _) => {
                     // bufsize = memory.accessed();
-                    let qt = self.libos.push(&mut connected_qd, memory);
+                    let qt = self
+                        .libos
+                        .push(&mut connected_qd, memory)
+                        .expect("Push queue full.");
                     qtokens.push(qt);
                     processed_packages += 1;
 
@@ -149,8 +152,25 @@ impl<
                     qtokens.push(qt);
                 }
                 CompletedRequest::Push(memory) => {
-                    self.libos.free(&mut connected_qd, memory);
+                    self.libos
+                        .free(&mut connected_qd, memory)
+                        .expect("Buffer freed twice.");
                 }
+                // Only push_copy completes with this and we never use it.
+                CompletedRequest::Sent => {}
+                CompletedRequest::Error(e) => panic!("Connection to client failed: {}", e),
+                CompletedRequest::EndOfStream => {
+                    println!("Client is done sending.");
+                    return;
+                }
+                CompletedRequest::Corrupt {
+                    expected, actual, ..
+                } => panic!(
+                    "Corrupt packet from client: expected {:#010x}, got {:#010x}.",
+                    expected, actual
+                ),
+                // Only sent with IoQueueConfig::notifications, which we don't turn on.
+                CompletedRequest::Notify => {}
             }
         }
     }
@@ -186,7 +206,9 @@ impl<
         let mut libos = IoQueue::new();
         let mut connection: QueueDescriptor = libos.socket();
 
-        libos.connect(&mut connection, &address, &port);
+        libos
+            .connect(&mut connection, &address, &port)
+            .expect("Unable to connect.");
 
         Self {
             stats: Statistics::new("client"),
@@ -210,7 +232,10 @@ impl<
             let (buf, stamp) = self.makepkt(self.bufsize);
             packet_times.insert(stamp, Instant::now());
 
-            let qt = self.libos.push(&mut self.qd, buf);
+            let qt = self
+                .libos
+                .push(&mut self.qd, buf)
+                .expect("Push queue full.");
             qtokens.push(qt);
             let qt = self.libos.pop(&mut self.qd);
             qtokens.push(qt);
@@ -221,12 +246,16 @@ impl<
             if last_log.elapsed() > Duration::from_secs(1) {
                 self.stats.print();
 
-                let total_io = self.libos.get_and_reset_time() as u64;
+                let timing = self.libos.timing(&self.qd);
+                self.libos.reset_timing(&mut self.qd);
+                let total_io = timing.push + timing.completions;
                 println!(
                     "libOS time ({:?}) / processed_packages ({:?}) = {:?}",
-                    Duration::from_nanos(total_io as u64),
+                    total_io,
                     processed_packages,
-                    Duration::from_nanos((total_io as f64 / processed_packages as f64) as u64),
+                    Duration::from_nanos(
+                        (total_io.as_nanos() as f64 / processed_packages as f64) as u64
+                    ),
                 );
                 processed_packages = 0;
                 last_log = Instant::now();
@@ -237,13 +266,18 @@ impl<
 
             match result {
                 CompletedRequest::Push(memory) => {
-                    self.libos.free(&mut self.qd, memory);
+                    self.libos
+                        .free(&mut self.qd, memory)
+                        .expect("Buffer freed twice.");
                 }
-                CompletedRequest::Pop(memory) => {
+                // Only push_copy completes with this and we never use it.
+                CompletedRequest::Sent => {}
+                CompletedRequest::Pop(memory, bufsize) => {
                     // Record statistics.
-                    let bufsize: usize = memory.accessed();
                     let stamp: u64 = Self::getstamp(&memory);
-                    self.libos.free(&mut self.qd, memory);
+                    self.libos
+                        .free(&mut self.qd, memory)
+                        .expect("Buffer freed twice.");
 
                     let timestamp = packet_times.remove(&stamp).unwrap();
                     self.stats.record(2 * bufsize, timestamp.elapsed());
@@ -252,13 +286,26 @@ impl<
                     let (buf, stamp) = self.makepkt(self.bufsize);
                     packet_times.insert(stamp, Instant::now());
 
-                    let qt = self.libos.push(&mut self.qd, buf);
+                    let qt = self
+                        .libos
+                        .push(&mut self.qd, buf)
+                        .expect("Push queue full.");
                     qtokens.push(qt);
 
                     let qt = self.libos.pop(&mut self.qd);
                     qtokens.push(qt);
                     processed_packages += 1;
                 }
+                CompletedRequest::Error(e) => panic!("Connection to server failed: {}", e),
+                CompletedRequest::EndOfStream => panic!("Server stopped sending."),
+                CompletedRequest::Corrupt {
+                    expected, actual, ..
+                } => panic!(
+                    "Corrupt packet from server: expected {:#010x}, got {:#010x}.",
+                    expected, actual
+                ),
+                // Only sent with IoQueueConfig::notifications, which we don't turn on.
+                CompletedRequest::Notify => {}
             }
         }
     }
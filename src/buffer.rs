@@ -0,0 +1,50 @@
+use rdma_cm::RdmaMemory;
+
+use crate::error::IoQueueError;
+
+/// Length accessors for buffers handed back by `wait`. After a `Pop` the buffer's length is the
+/// number of bytes that actually arrived, so `as_slice()` covers exactly the received data.
+pub trait BufferExt {
+    /// Valid bytes in the buffer: what was received for a pop, what was written for a push.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Total size of the buffer, i.e. `BUFFER_SIZE`.
+    fn capacity(&self) -> usize;
+    /// The first `len` valid bytes. Fails instead of reading past what was received or written.
+    fn checked_slice(&self, len: usize) -> Result<&[u8], IoQueueError>;
+    /// `as_mut_slice(len)`, but fails if `len` is more than the buffer can hold.
+    fn checked_mut_slice(&mut self, len: usize) -> Result<&mut [u8], IoQueueError>;
+}
+
+impl<const SIZE: usize> BufferExt for RdmaMemory<u8, SIZE> {
+    fn len(&self) -> usize {
+        self.accessed()
+    }
+
+    fn capacity(&self) -> usize {
+        SIZE
+    }
+
+    fn checked_slice(&self, len: usize) -> Result<&[u8], IoQueueError> {
+        let available = self.len();
+        if len > available {
+            return Err(IoQueueError::OutOfBounds {
+                requested: len,
+                available,
+            });
+        }
+        Ok(&self.as_slice()[..len])
+    }
+
+    fn checked_mut_slice(&mut self, len: usize) -> Result<&mut [u8], IoQueueError> {
+        if len > SIZE {
+            return Err(IoQueueError::OutOfBounds {
+                requested: len,
+                available: SIZE,
+            });
+        }
+        Ok(self.as_mut_slice(len))
+    }
+}
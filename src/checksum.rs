@@ -0,0 +1,72 @@
+//! Optional CRC32 integrity check on every buffer sent, see `IoQueueConfig::checksum`. The
+//! checksum is prepended to the payload on push and stripped again before the buffer is handed
+//! back to the user.
+use rdma_cm::RdmaMemory;
+
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// Standard (IEEE, reflected) CRC32 lookup table.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Shift the payload over and write its checksum in front of it. Returns false if the buffer
+/// has no room left for the checksum.
+pub fn prepend<const SIZE: usize>(memory: &mut RdmaMemory<u8, SIZE>) -> bool {
+    let length = memory.accessed();
+    if length + CHECKSUM_SIZE > SIZE {
+        return false;
+    }
+    let buffer = memory.as_mut_slice(length + CHECKSUM_SIZE);
+    buffer.copy_within(0..length, CHECKSUM_SIZE);
+    let crc = crc32(&buffer[CHECKSUM_SIZE..]);
+    buffer[..CHECKSUM_SIZE].copy_from_slice(&crc.to_le_bytes());
+    true
+}
+
+/// Remove the checksum written by `prepend`, leaving just the payload. Returns the
+/// `(expected, actual)` checksums if they don't match.
+pub fn strip<const SIZE: usize>(memory: &mut RdmaMemory<u8, SIZE>) -> Option<(u32, u32)> {
+    let length = memory.accessed();
+    if length < CHECKSUM_SIZE {
+        // Too short to even carry a checksum.
+        return Some((0, crc32(memory.as_slice())));
+    }
+    let buffer = memory.as_mut_slice(length);
+    let mut expected = [0; CHECKSUM_SIZE];
+    expected.copy_from_slice(&buffer[..CHECKSUM_SIZE]);
+    let expected = u32::from_le_bytes(expected);
+    let actual = crc32(&buffer[CHECKSUM_SIZE..]);
+    buffer.copy_within(CHECKSUM_SIZE..length, 0);
+    memory.initialize_length(length - CHECKSUM_SIZE);
+
+    if expected != actual {
+        Some((expected, actual))
+    } else {
+        None
+    }
+}
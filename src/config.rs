@@ -0,0 +1,183 @@
+use crate::error::IoQueueError;
+use std::time::Duration;
+
+/// Runtime knobs for an `IoQueue`. Anything that determines memory layout stays a const generic
+/// on `IoQueue`; everything here can be chosen at startup.
+#[derive(Debug, Clone)]
+pub struct IoQueueConfig {
+    /// How long a connection may go without any completion before we probe the peer with a
+    /// zero-byte RDMA write. `None` disables keepalive.
+    pub keepalive_interval: Option<Duration>,
+    /// How long a keepalive probe may stay unanswered before the connection is declared dead.
+    pub keepalive_timeout: Duration,
+    /// How many consecutive `wait` iterations may pass without any completion on a push's
+    /// connection, while the peer advertises no send windows, before we warn that the connection
+    /// is flow-control stalled. `None` disables the check.
+    pub stall_warning_iterations: Option<usize>,
+    /// On a detected stall also return `CompletedRequest::Error(FlowControlStalled)` from
+    /// `wait` instead of only logging. The push stays queued, so its token can be waited on
+    /// again.
+    pub stall_error: bool,
+    /// How many pushes may be queued per connection waiting for send windows before `push`
+    /// starts returning `WouldBlock`. Defaults to `WINDOW_SIZE` when `None`. The queue is
+    /// allocated at this size when the connection is set up and never grows, so size it for the
+    /// largest burst you expect rather than the average.
+    pub push_queue_capacity: Option<usize>,
+    /// Most completions handed to the completions coroutine per poll. Smaller batches bound how
+    /// long one poll can take while keeping a deep CQ. Defaults to `CQ_ELEMENTS` when `None`.
+    pub cq_poll_batch: Option<usize>,
+    /// Each connection's memory pool holds `pool_overprovision * WINDOW_SIZE` buffers. Must be at
+    /// least 1 so the whole receive window can be posted. More buffers mean `malloc` is less
+    /// likely to run dry while pushes are in flight.
+    pub pool_overprovision: usize,
+    /// Hold back receive window updates to the peer until at least this many windows are
+    /// pending. Updates are still sent after `window_update_delay`, or right away once the peer
+    /// has used up every window it knows about. `0` sends an update on every replenishment.
+    pub window_update_threshold: u64,
+    /// Longest a receive window update is held back waiting for `window_update_threshold`
+    /// windows to pile up.
+    pub window_update_delay: Duration,
+    /// Prepend a CRC32 to every pushed payload and verify it on receive, surfacing mismatches as
+    /// `CompletedRequest::Corrupt`. Costs CPU and 4 bytes of every buffer, so only turn this on for
+    /// debugging. Both sides of a connection must agree on it.
+    pub checksum: bool,
+    /// Put a one byte header in front of every pushed payload so the doorbells sent by
+    /// `IoQueue::notify` can be told apart from data. Costs 1 byte of every buffer. Both sides
+    /// of a connection must agree on it.
+    pub notifications: bool,
+    /// Receive buffers posted while the connection is being set up, before the first message can
+    /// arrive. Defaults to `WINDOW_SIZE` when `None`. Must fit in both the memory pool and the
+    /// receive queue (`RECV_WRS`). Anything below `WINDOW_SIZE / 2` is topped up on the next poll.
+    pub initial_recv_posts: Option<usize>,
+    /// How many more times `connect` tries to resolve the peer's address after the first
+    /// attempt fails, waiting `address_resolution_retry_delay` in between. `0` fails right away.
+    pub address_resolution_retries: usize,
+    /// How long `connect` waits before each retry of `address_resolution_retries`. Connections
+    /// that are already up keep being polled meanwhile.
+    pub address_resolution_retry_delay: Duration,
+    /// Share one protection domain and memory pool between every connection accepted from the
+    /// same listener instead of allocating both per connection. The pool is sized for this many
+    /// connections at once (`pool_overprovision * WINDOW_SIZE` buffers each). All accepted
+    /// connections must be on the same device as the first one.
+    pub shared_accept_pool: Option<usize>,
+    /// Write to every page of a new memory pool while setting up the connection, so the first
+    /// transfers don't pay for page faults.
+    pub prefault_pool: bool,
+    /// `mlockall(MCL_CURRENT)` once a pool has been set up, keeping it and everything else
+    /// currently mapped in RAM. Needs a large enough `RLIMIT_MEMLOCK`; failing only logs a
+    /// warning.
+    pub lock_memory: bool,
+    /// `push` fails with `WouldBlock` once this many work requests are posted or queued on a
+    /// connection, see `IoQueue::outstanding`. Posted receive buffers count too, so leave room
+    /// for a receive window or two. `None` only relies on the push queue and flow control.
+    pub max_outstanding: Option<usize>,
+    /// Keep posted work requests in a ring of this many slots indexed by `work_id % work_slots`
+    /// instead of a hash map, saving a hash and possibly an allocation per operation. Sized at
+    /// around `RECV_WRS + SEND_WRS` almost nothing collides; requests that do fall back to the
+    /// hash map. `None` uses only the hash map.
+    pub work_slots: Option<usize>,
+    /// Record how long every push takes from `push` to its send completion, and every receive
+    /// buffer from being posted to being filled, see `IoQueue::latency_percentiles`. Costs a
+    /// clock read per operation.
+    pub latency_histograms: bool,
+    /// Fail `push` with `NoSendWindows` when the peer hasn't granted a send window for it,
+    /// instead of queueing it until one arrives. Lets the application decide where to buffer or
+    /// shed load.
+    pub fail_without_send_windows: bool,
+    /// How long tearing a connection down keeps polling for sends the NIC hasn't finished with.
+    /// If some are still in flight after this, the connection's registered memory is leaked
+    /// rather than freed while the NIC might still be using it.
+    pub teardown_drain_timeout: Duration,
+    /// Longest message `push_message` sends and `recv_message` reassembles. A fragment claiming
+    /// a longer message fails `recv_message` with `MalformedFragment` instead of having us
+    /// allocate whatever length the peer put on the wire.
+    pub max_message_length: usize,
+}
+
+impl Default for IoQueueConfig {
+    fn default() -> Self {
+        IoQueueConfig {
+            keepalive_interval: None,
+            keepalive_timeout: Duration::from_secs(1),
+            stall_warning_iterations: Some(1_000_000),
+            stall_error: false,
+            push_queue_capacity: None,
+            cq_poll_batch: None,
+            pool_overprovision: 2,
+            window_update_threshold: 0,
+            window_update_delay: Duration::from_micros(50),
+            checksum: false,
+            notifications: false,
+            initial_recv_posts: None,
+            address_resolution_retries: 3,
+            address_resolution_retry_delay: Duration::from_millis(100),
+            shared_accept_pool: None,
+            prefault_pool: false,
+            lock_memory: false,
+            max_outstanding: None,
+            work_slots: None,
+            latency_histograms: false,
+            fail_without_send_windows: false,
+            teardown_drain_timeout: Duration::from_secs(1),
+            max_message_length: 64 << 20,
+        }
+    }
+}
+
+/// Per-connection tuning applied while a connection is set up by `IoQueue::accept_with` or
+/// `connect_with`, or later with `apply_settings`. Set it up once and reuse it for every
+/// connection that should behave the same. The defaults match a connection none of the `set_*`
+/// methods were called on.
+#[derive(Debug, Clone)]
+pub struct ConnectionSettings {
+    /// See `IoQueue::set_priority`.
+    pub poll_every: usize,
+    /// See `IoQueue::set_send_batch`.
+    pub send_batch: Option<usize>,
+    /// See `IoQueue::set_rate_limit`.
+    pub rate_limit: Option<u64>,
+    /// See `IoQueue::set_core_affinity`.
+    pub core_affinity: Option<usize>,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            poll_every: 1,
+            send_batch: None,
+            rate_limit: None,
+            core_affinity: None,
+        }
+    }
+}
+
+impl IoQueueConfig {
+    pub(crate) fn validate(&self, window_size: usize, recv_wrs: usize) -> Result<(), IoQueueError> {
+        if self.pool_overprovision < 1 {
+            return Err(IoQueueError::InvalidConfig(
+                "pool_overprovision must be at least 1",
+            ));
+        }
+        if let Some(posts) = self.initial_recv_posts {
+            if posts > self.pool_overprovision * window_size {
+                return Err(IoQueueError::InvalidConfig(
+                    "initial_recv_posts exceeds the memory pool size",
+                ));
+            }
+            if posts > recv_wrs {
+                return Err(IoQueueError::InvalidConfig(
+                    "initial_recv_posts exceeds RECV_WRS",
+                ));
+            }
+        }
+        if self.shared_accept_pool == Some(0) {
+            return Err(IoQueueError::InvalidConfig(
+                "shared_accept_pool must be sized for at least one connection",
+            ));
+        }
+        if self.work_slots == Some(0) {
+            return Err(IoQueueError::InvalidConfig("work_slots must be at least 1"));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,129 @@
+//! A fixed set of connections to one server, handed out one at a time.
+use std::ops::{Deref, DerefMut};
+
+use crate::error::IoQueueError;
+use crate::{IoQueue, QueueDescriptor};
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn, Level};
+
+/// `size` connections to `node:service`, all established up front. `checkout` hands out the
+/// idle connection with the least outstanding work, reconnecting it first if it has died.
+/// Every connection belongs to the `IoQueue` the pool was created with, so pass the same one
+/// to every call.
+pub struct ConnectionPool {
+    node: String,
+    service: String,
+    /// `None` while checked out.
+    connections: Vec<Option<QueueDescriptor>>,
+}
+
+/// A connection checked out of a `ConnectionPool`. Derefs to its `QueueDescriptor`, so it can be
+/// passed to `IoQueue` methods directly. Hand it back with `ConnectionPool::checkin`.
+pub struct PooledConnection {
+    slot: usize,
+    qd: QueueDescriptor,
+}
+
+impl Deref for PooledConnection {
+    type Target = QueueDescriptor;
+
+    fn deref(&self) -> &QueueDescriptor {
+        &self.qd
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut QueueDescriptor {
+        &mut self.qd
+    }
+}
+
+impl ConnectionPool {
+    pub fn connect<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    >(
+        io_queue: &mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        node: &str,
+        service: &str,
+        size: usize,
+    ) -> Result<ConnectionPool, IoQueueError> {
+        info!("Connecting pool of {} to {}:{}.", size, node, service);
+
+        let mut connections = Vec::with_capacity(size);
+        for _ in 0..size {
+            connections.push(Some(Self::new_connection(io_queue, node, service)?));
+        }
+        Ok(ConnectionPool {
+            node: node.to_string(),
+            service: service.to_string(),
+            connections,
+        })
+    }
+
+    /// Take the idle connection with the least outstanding work. Fails with `WouldBlock` if
+    /// every connection is checked out, or with the error from `connect` if a dead connection
+    /// can't be re-established.
+    pub fn checkout<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        io_queue: &mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+    ) -> Result<PooledConnection, IoQueueError> {
+        let slot = self
+            .connections
+            .iter()
+            .enumerate()
+            .filter_map(|(slot, qd)| qd.as_ref().map(|qd| (slot, qd)))
+            .min_by_key(|(_, qd)| match io_queue.connection_error(qd) {
+                // Dead connections have nothing outstanding, but reconnecting is slow.
+                Some(_) => usize::MAX,
+                None => io_queue.outstanding(qd),
+            })
+            .map(|(slot, _)| slot)
+            .ok_or(IoQueueError::WouldBlock)?;
+
+        let mut qd = self.connections[slot].take().unwrap();
+        if let Some(e) = io_queue.connection_error(&qd) {
+            warn!("Pooled connection {} failed ({}), reconnecting.", slot, e);
+            io_queue.abandon(&mut qd);
+            match Self::new_connection(io_queue, &self.node, &self.service) {
+                Ok(new) => qd = new,
+                Err(e) => {
+                    // Leave the dead one in place so the next checkout retries.
+                    self.connections[slot] = Some(qd);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(PooledConnection { slot, qd })
+    }
+
+    pub fn checkin(&mut self, connection: PooledConnection) {
+        debug_assert!(self.connections[connection.slot].is_none());
+        self.connections[connection.slot] = Some(connection.qd);
+    }
+
+    fn new_connection<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    >(
+        io_queue: &mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        node: &str,
+        service: &str,
+    ) -> Result<QueueDescriptor, IoQueueError> {
+        let mut qd = io_queue.socket();
+        io_queue.connect(&mut qd, node, service)?;
+        Ok(qd)
+    }
+}
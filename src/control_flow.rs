@@ -1,12 +1,121 @@
 use rdma_cm::{PeerConnectionData, QueuePair, RdmaMemory, VolatileRdmaMemory};
-use std::array::IntoIter;
+use std::time::{Duration, Instant};
 
 #[allow(unused_imports)]
 use tracing::{debug, info, span, trace, Level};
 
-pub struct ControlFlow<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize> {
+/// Work id used for keepalive probes so their completions can be told apart from window updates.
+pub const KEEPALIVE_WORK_ID: u64 = u64::MAX;
+
+/// Work id of window updates. Out of the way of the ids handed out to buffers, so their
+/// completions are never taken for a buffer's.
+pub const WINDOW_UPDATE_WORK_ID: u64 = u64::MAX - 1;
+
+/// Receive side credit accounting, kept apart from the queue pair so it can be tested without a
+/// device.
+pub struct RecvCredits<const RECV_WRS: usize> {
     /// Amount of allocated buffers left for receive requests.
     remaining_receive_window: u64,
+    /// Receive windows we have posted but not told the peer about yet.
+    unadvertised_windows: u64,
+    /// When the oldest of `unadvertised_windows` was posted.
+    unadvertised_since: Option<Instant>,
+    /// Only advertise once at least this many windows are pending...
+    update_threshold: u64,
+    /// ...or they have been pending for this long.
+    update_delay: Duration,
+}
+
+impl<const RECV_WRS: usize> RecvCredits<RECV_WRS> {
+    pub fn new() -> RecvCredits<RECV_WRS> {
+        RecvCredits {
+            remaining_receive_window: 0,
+            unadvertised_windows: 0,
+            unadvertised_since: None,
+            update_threshold: 0,
+            update_delay: Duration::from_secs(0),
+        }
+    }
+
+    pub fn set_update_coalescing(&mut self, threshold: u64, delay: Duration) {
+        self.update_threshold = threshold;
+        self.update_delay = delay;
+    }
+
+    pub fn remaining(&self) -> u64 {
+        self.remaining_receive_window
+    }
+
+    /// Window counts never wrap. Going below zero or past what the queue pair can hold is a bug
+    /// in the accounting, caught by the debug assertions here and in `check`; release builds
+    /// clamp instead so flow control degrades rather than corrupting the counters.
+    pub fn subtract(&mut self, how_many: u64) {
+        debug_assert!(
+            self.remaining_receive_window >= how_many,
+            "{} receive windows used, but only {} posted",
+            how_many,
+            self.remaining_receive_window
+        );
+        self.remaining_receive_window = self.remaining_receive_window.saturating_sub(how_many);
+        self.unadvertised_windows = self.unadvertised_windows.min(self.remaining_receive_window);
+    }
+
+    /// Checks the unclamped sums so an overflow trips the debug assertions before being clamped.
+    pub fn add(&mut self, how_many: u64) {
+        let remaining = self.remaining_receive_window.saturating_add(how_many);
+        let unadvertised = self.unadvertised_windows.saturating_add(how_many);
+        Self::check_counts(remaining, unadvertised);
+        self.remaining_receive_window = remaining.min(RECV_WRS as u64);
+        self.unadvertised_windows = unadvertised.min(self.remaining_receive_window);
+        self.unadvertised_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Debug-asserts that we never hold more receive credits than the receive queue has room
+    /// for, and never owe the peer more of them than we hold. Free in release builds.
+    pub fn check(&self) {
+        Self::check_counts(self.remaining_receive_window, self.unadvertised_windows);
+    }
+
+    fn check_counts(remaining: u64, unadvertised: u64) {
+        debug_assert!(
+            remaining <= RECV_WRS as u64,
+            "{} receive windows posted, but RECV_WRS is {}",
+            remaining,
+            RECV_WRS
+        );
+        debug_assert!(
+            unadvertised <= remaining,
+            "{} unadvertised receive windows out of {} posted",
+            unadvertised,
+            remaining
+        );
+    }
+
+    /// Take the pending receive windows if it's time to tell the peer about them: there are
+    /// enough of them, they've waited long enough, or the peer has used up everything we told it
+    /// about. The last case means we never starve the sender while holding back credits.
+    pub fn take_due(&mut self) -> Option<u64> {
+        if self.unadvertised_windows == 0 {
+            return None;
+        }
+        let peer_starved = self.remaining_receive_window == self.unadvertised_windows;
+        let due = self.unadvertised_windows >= self.update_threshold
+            || peer_starved
+            || self
+                .unadvertised_since
+                .is_none_or(|since| since.elapsed() >= self.update_delay);
+        if !due {
+            return None;
+        }
+
+        self.unadvertised_since = None;
+        Some(std::mem::replace(&mut self.unadvertised_windows, 0))
+    }
+}
+
+pub struct ControlFlow<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize> {
+    /// Receive windows we have posted, and how many of them the peer knows about.
+    credits: RecvCredits<RECV_WRS>,
     /// Amount of allocated buffers left on the other side. This is our local variable used
     /// for our own internal tracking. The other side will actually write to
     /// `volatile_send_window` when updating their send windows.
@@ -42,7 +151,7 @@ impl<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize>
         other_side: PeerConnectionData<u64, 1>,
     ) -> ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE> {
         ControlFlow {
-            remaining_receive_window: 0,
+            credits: RecvCredits::new(),
             // Other side will allocate same number of buffers we do.
             remaining_send_window: 0,
             volatile_send_window: volatile_receive_window,
@@ -52,6 +161,11 @@ impl<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize>
         }
     }
 
+    /// Coalesce window updates to the peer: see `IoQueueConfig::window_update_threshold`.
+    pub fn set_update_coalescing(&mut self, threshold: u64, delay: Duration) {
+        self.credits.set_update_coalescing(threshold, delay);
+    }
+
     pub fn other_side_recv_windows(&self) -> u64 {
         self.volatile_send_window.read()[0]
     }
@@ -65,36 +179,154 @@ impl<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize>
     }
 
     pub fn remaining_receive_windows(&self) -> u64 {
-        self.remaining_receive_window
+        self.credits.remaining()
     }
 
+    /// See `RecvCredits::subtract`.
     pub fn subtract_recv_windows(&mut self, how_many: u64) {
-        assert!(self.remaining_receive_window >= how_many);
-        self.remaining_receive_window -= how_many;
+        self.credits.subtract(how_many);
     }
 
     pub fn subtract_remaining_send_windows(&mut self, how_many: u64) {
-        assert!(self.remaining_send_window >= how_many);
-        self.remaining_send_window -= how_many;
+        debug_assert!(self.remaining_send_window >= how_many);
+        self.remaining_send_window = self.remaining_send_window.saturating_sub(how_many);
     }
 
     /// Receive windows should only be added when we hit zero?
     pub fn add_recv_windows(&mut self, how_many: u64) {
         tracing::info!("add_recv_windows(how_many={})!", how_many);
         // assert_eq!(self.remaining_receive_window, 0);
-        self.remaining_receive_window += how_many;
+        self.credits.add(how_many);
+        self.maybe_advertise();
+    }
+
+    /// See `RecvCredits::check`.
+    pub fn check_credits(&self) {
+        self.credits.check();
+    }
+
+    /// Tell the peer about pending receive windows once `RecvCredits::take_due` says so.
+    pub fn maybe_advertise(&mut self) {
+        if let Some(how_many) = self.credits.take_due() {
+            self.advertise_recv_windows(how_many);
+        }
+    }
+
+    fn advertise_recv_windows(&mut self, how_many: u64) {
+        trace!("{}: {}", crate::function_name!(), how_many);
 
         let mut memory = self.memory.take().unwrap();
         memory.as_mut_slice(1)[0] = how_many;
-        // This work_id number does not matter. The completion queue coroutine will not look at it.
-        let wr = [(0, memory)];
+        let wr = [(WINDOW_UPDATE_WORK_ID, memory)];
         // We don't really care when this gets done... So no need for the completion queue to
         // inform us when it happens.
         self.qp
             .post_send(wr.iter(), self.other_side.as_rdma_write());
 
         // Give us back our memory.
-        let (_, memory) = IntoIter::new(wr).next().unwrap();
+        let (_, memory) = IntoIterator::into_iter(wr).next().unwrap();
         self.memory = Some(memory);
     }
+
+    /// Zero-byte RDMA write to our peer's window slot. Nothing is actually written, but the NIC
+    /// still needs an ack from the other side, so a dead peer shows up as an error completion.
+    pub fn send_keepalive(&mut self) {
+        trace!("{}", crate::function_name!());
+
+        let mut memory = self.memory.take().unwrap();
+        memory.reset_access();
+        let wr = [(KEEPALIVE_WORK_ID, memory)];
+        self.qp
+            .post_send(wr.iter(), self.other_side.as_rdma_write());
+
+        let (_, memory) = IntoIterator::into_iter(wr).next().unwrap();
+        self.memory = Some(memory);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RecvCredits;
+    use std::time::Duration;
+
+    const RECV_WRS: usize = 8;
+
+    fn coalescing(threshold: u64) -> RecvCredits<RECV_WRS> {
+        let mut credits = RecvCredits::new();
+        credits.set_update_coalescing(threshold, Duration::from_secs(3600));
+        credits
+    }
+
+    #[test]
+    fn add_and_subtract_within_limits() {
+        let mut credits = coalescing(1);
+        credits.add(RECV_WRS as u64);
+        assert_eq!(credits.remaining(), RECV_WRS as u64);
+        assert_eq!(credits.take_due(), Some(RECV_WRS as u64));
+        credits.subtract(3);
+        assert_eq!(credits.remaining(), RECV_WRS as u64 - 3);
+        credits.check();
+    }
+
+    #[test]
+    fn subtract_clamps_unadvertised_to_remaining() {
+        let mut credits = coalescing(u64::MAX);
+        credits.add(4);
+        credits.subtract(3);
+        assert_eq!(credits.unadvertised_windows, 1);
+        credits.check();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "receive windows posted, but RECV_WRS is 8")]
+    fn add_past_recv_wrs_asserts() {
+        let mut credits = coalescing(1);
+        credits.add(RECV_WRS as u64);
+        credits.add(1);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "receive windows used, but only 2 posted")]
+    fn subtract_past_zero_asserts() {
+        let mut credits = coalescing(1);
+        credits.add(2);
+        credits.subtract(3);
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn release_builds_clamp() {
+        let mut credits = coalescing(u64::MAX);
+        credits.add(RECV_WRS as u64 + 5);
+        assert_eq!(credits.remaining(), RECV_WRS as u64);
+        assert_eq!(credits.unadvertised_windows, RECV_WRS as u64);
+        credits.subtract(u64::MAX);
+        assert_eq!(credits.remaining(), 0);
+        assert_eq!(credits.unadvertised_windows, 0);
+    }
+
+    #[test]
+    fn take_due_waits_for_threshold() {
+        let mut credits = coalescing(4);
+        credits.add(4);
+        // Everything posted is unadvertised, so the peer is starved and this goes out at once.
+        assert_eq!(credits.take_due(), Some(4));
+        credits.add(2);
+        assert_eq!(credits.take_due(), None);
+        credits.add(2);
+        assert_eq!(credits.take_due(), Some(4));
+        assert_eq!(credits.take_due(), None);
+    }
+
+    #[test]
+    fn take_due_after_delay() {
+        let mut credits = RecvCredits::<RECV_WRS>::new();
+        credits.set_update_coalescing(u64::MAX, Duration::from_secs(0));
+        credits.add(4);
+        credits.take_due();
+        credits.add(1);
+        assert_eq!(credits.take_due(), Some(1));
+    }
 }
@@ -0,0 +1,217 @@
+use rdma_cm::RdmaMemory;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoQueueError {
+    /// A keepalive probe went unanswered for longer than `keepalive_timeout`.
+    PeerUnresponsive,
+    /// The NIC reported a work completion with this (non-success) `ibv_wc_status`.
+    WorkCompletion(u32),
+    /// The peer has advertised no send windows and nothing has completed for a while.
+    FlowControlStalled,
+    /// The push was already handed to the NIC, so it can no longer be cancelled.
+    AlreadyPosted,
+    /// The connection's push queue is full. Wait for some outstanding pushes to complete and
+    /// try again.
+    WouldBlock,
+    /// `IoQueueConfig` contained a value we can't run with.
+    InvalidConfig(&'static str),
+    /// A received buffer didn't carry a valid fragment header. Both sides of a connection must
+    /// use `push_message`/`recv_message` for framing to work.
+    MalformedFragment,
+    /// The payload doesn't fit: messages are limited to `u32::MAX` bytes by the fragment header,
+    /// and with `checksum` on a pushed buffer needs 4 spare bytes for the CRC.
+    MessageTooLarge,
+    /// The buffer passed to `push` was allocated by a different connection's `malloc`, so it is
+    /// registered with the wrong protection domain.
+    ForeignBuffer,
+    /// The connection was shut down, or its internal push queue was torn down.
+    ConnectionClosed,
+    /// A received payload didn't match its CRC32.
+    ChecksumMismatch { expected: u32, actual: u32 },
+    /// `connect` couldn't resolve the peer's address, even after retrying.
+    AddressUnresolved,
+    /// `connected_pair` couldn't bind or listen on its address, e.g. because it isn't local or
+    /// is already in use.
+    BindFailed,
+    /// `free` was given a buffer that is already back in the memory pool. `free` takes buffers by
+    /// value, so this means the pool handed the same region out twice.
+    DoubleFree,
+    /// `push` after `shutdown_send` on the same connection.
+    SendShutdown,
+    /// `flush` gave up with `unposted` pushes still waiting to be posted.
+    FlushTimedOut { unposted: usize },
+    /// `pop_typed` received a message that isn't the size of the type it was asked for.
+    LengthMismatch { expected: usize, actual: usize },
+    /// The server rejected our connection request or couldn't be reached, e.g. because it is
+    /// overloaded or nothing is listening.
+    ConnectionRefused,
+    /// `await_reply` popped a reply whose correlation id matches no outstanding `call`.
+    UnknownCorrelationId(u64),
+    /// The peer has no receive buffer left for this push, see
+    /// `IoQueueConfig::fail_without_send_windows`.
+    NoSendWindows,
+    /// `checked_slice`/`checked_mut_slice` asked for more bytes than the buffer has: its
+    /// received or written length when reading, `BUFFER_SIZE` when writing. Also a one-sided
+    /// write running past the end of its `RemoteRegion`.
+    OutOfBounds { requested: usize, available: usize },
+    /// The token or queue descriptor was handed out by a different `IoQueue`.
+    WrongInstance,
+    /// `push` of a buffer with nothing written to it. The peer would take a zero length send
+    /// for the end-of-stream marker of `shutdown_send`.
+    EmptyPush,
+    /// The push was cancelled before it was posted. What waiting on its token returns, and what
+    /// cancelling it again fails with.
+    Cancelled,
+}
+
+impl fmt::Display for IoQueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoQueueError::PeerUnresponsive => write!(f, "peer did not answer keepalive probe"),
+            IoQueueError::WorkCompletion(status) => {
+                write!(f, "work completion failed with status {}", status)
+            }
+            IoQueueError::FlowControlStalled => write!(
+                f,
+                "connection is flow-control stalled: peer has advertised no send windows"
+            ),
+            IoQueueError::AlreadyPosted => write!(f, "push already posted, can't cancel it"),
+            IoQueueError::WouldBlock => write!(f, "push queue is full"),
+            IoQueueError::InvalidConfig(reason) => write!(f, "invalid config: {}", reason),
+            IoQueueError::MalformedFragment => write!(f, "received malformed message fragment"),
+            IoQueueError::MessageTooLarge => write!(f, "message too large"),
+            IoQueueError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: expected {:#010x}, got {:#010x}",
+                expected, actual
+            ),
+            IoQueueError::ConnectionClosed => write!(f, "connection closed"),
+            IoQueueError::ConnectionRefused => write!(f, "connection refused by server"),
+            IoQueueError::NoSendWindows => write!(f, "peer has granted no send windows"),
+            IoQueueError::UnknownCorrelationId(id) => {
+                write!(f, "reply for unknown call {}", id)
+            }
+            IoQueueError::AddressUnresolved => write!(f, "unable to resolve address"),
+            IoQueueError::BindFailed => write!(f, "unable to bind listener"),
+            IoQueueError::DoubleFree => write!(f, "buffer freed twice"),
+            IoQueueError::SendShutdown => write!(f, "send direction already shut down"),
+            IoQueueError::LengthMismatch { expected, actual } => write!(
+                f,
+                "expected a {} byte message, received {} bytes",
+                expected, actual
+            ),
+            IoQueueError::FlushTimedOut { unposted } => {
+                write!(f, "flush timed out with {} pushes not yet posted", unposted)
+            }
+            IoQueueError::OutOfBounds {
+                requested,
+                available,
+            } => write!(
+                f,
+                "requested {} bytes of a buffer with only {} available",
+                requested, available
+            ),
+            IoQueueError::WrongInstance => write!(f, "token belongs to a different IoQueue"),
+            IoQueueError::Cancelled => write!(f, "push was cancelled"),
+            IoQueueError::EmptyPush => write!(
+                f,
+                "can't push an empty buffer, zero length sends are reserved for shutdown_send"
+            ),
+            IoQueueError::ForeignBuffer => write!(
+                f,
+                "buffer belongs to a different connection; did you mix up queue descriptors?"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IoQueueError {}
+
+/// A push that was refused. Hands the memory back so the caller can retry or `free` it.
+pub struct PushError<const SIZE: usize> {
+    pub error: IoQueueError,
+    pub memory: RdmaMemory<u8, SIZE>,
+}
+
+impl<const SIZE: usize> fmt::Debug for PushError<SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PushError")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl<const SIZE: usize> fmt::Display for PushError<SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<const SIZE: usize> std::error::Error for PushError<SIZE> {}
+
+impl<const SIZE: usize> From<PushError<SIZE>> for IoQueueError {
+    fn from(e: PushError<SIZE>) -> Self {
+        e.error
+    }
+}
+
+/// A failed `request_reply`. Hands the request back if it never made it into the push queue, so
+/// the caller can retry or `free` it. Otherwise it went back to the pool.
+pub struct RequestError<const SIZE: usize> {
+    pub error: IoQueueError,
+    pub request: Option<RdmaMemory<u8, SIZE>>,
+}
+
+impl<const SIZE: usize> fmt::Debug for RequestError<SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RequestError")
+            .field("error", &self.error)
+            .field("returned", &self.request.is_some())
+            .finish()
+    }
+}
+
+impl<const SIZE: usize> fmt::Display for RequestError<SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<const SIZE: usize> std::error::Error for RequestError<SIZE> {}
+
+impl<const SIZE: usize> From<RequestError<SIZE>> for IoQueueError {
+    fn from(e: RequestError<SIZE>) -> Self {
+        e.error
+    }
+}
+
+/// A `free` or `free_all` that was refused. Hands back every buffer that didn't go into the
+/// pool.
+pub struct FreeError<const SIZE: usize> {
+    pub error: IoQueueError,
+    pub memory: Vec<RdmaMemory<u8, SIZE>>,
+}
+
+impl<const SIZE: usize> fmt::Debug for FreeError<SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FreeError")
+            .field("error", &self.error)
+            .field("returned", &self.memory.len())
+            .finish()
+    }
+}
+
+impl<const SIZE: usize> fmt::Display for FreeError<SIZE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl<const SIZE: usize> std::error::Error for FreeError<SIZE> {}
+
+impl<const SIZE: usize> From<FreeError<SIZE>> for IoQueueError {
+    fn from(e: FreeError<SIZE>) -> Self {
+        e.error
+    }
+}
@@ -1,26 +1,39 @@
 use async_channel;
+use futures::future::poll_fn;
 use futures::stream::StreamExt;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use nix::sys::mman::{mlockall, MlockAllFlags};
 use rdma_cm::PostSendOpcode;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell, RefMut};
 use std::collections::VecDeque;
+use std::fmt;
 use std::future::Future;
+use std::mem::ManuallyDrop;
 use std::ops::Deref;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::task::{Context, Poll};
 
 #[allow(unused_imports)]
-use tracing::{debug, error, info, span, trace, Level};
+use tracing::{debug, error, info, span, trace, warn, Level};
 
 use crate::function_name;
 
-use rdma_cm::{CompletionQueue, ProtectionDomain, QueuePair, RdmaMemory};
-
-use crate::control_flow::ControlFlow;
+use rdma_cm::{CommunicationManager, CompletionQueue, ProtectionDomain, QueuePair, RdmaMemory};
+
+use crate::checksum;
+use crate::config::{ConnectionSettings, IoQueueConfig};
+use crate::control_flow::{ControlFlow, KEEPALIVE_WORK_ID};
+use crate::error::{FreeError, IoQueueError, PushError};
+use crate::latency::{ConnectionLatency, Percentiles};
+use crate::memory_pool::{self, ConnectionResources, MemoryPoolFactory, SharedMemoryPool};
+use crate::notify;
+use crate::rate_limit::{Refilled, SharedRateLimit, TokenBucket};
+use crate::work_slots::WorkSlots;
 use futures::Stream;
 use std::cmp::min;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 pub(crate) struct Executor<
     const RECV_WRS: usize,
@@ -29,70 +42,540 @@ pub(crate) struct Executor<
     const WINDOW_SIZE: usize,
     const BUFFER_SIZE: usize,
 > {
-    tasks: Vec<ConnectionTask<RECV_WRS, SEND_WRS, WINDOW_SIZE, BUFFER_SIZE>>,
+    /// Indexed by `TaskHandle`. Slots of connections that have shut down are left empty so
+    /// handles stay valid.
+    tasks: Vec<Option<ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>>>,
+    config: IoQueueConfig,
+    /// Builds the memory pool of every new connection.
+    pool_factory: MemoryPoolFactory<BUFFER_SIZE>,
+    /// Resources shared by the connections accepted from a listener, see
+    /// `IoQueueConfig::shared_accept_pool`. Indexed by `QueueDescriptor::accept_resources`. Kept
+    /// until the executor goes away.
+    accept_resources: Vec<ConnectionResources<BUFFER_SIZE>>,
+    /// Stamped into every `TaskHandle`, so handles from another `IoQueue` are caught instead of
+    /// indexing into the wrong `tasks`.
+    instance: u64,
+}
+
+static NEXT_INSTANCE: AtomicU64 = AtomicU64::new(0);
+
+static LEAKED_TEARDOWNS: AtomicU64 = AtomicU64::new(0);
+
+/// How many connections this process has torn down with sends still in flight after
+/// `IoQueueConfig::teardown_drain_timeout`, leaking their registered memory. Meant for tests and
+/// debugging: it only goes up when tokens weren't waited on before shutting down.
+pub fn leaked_teardowns() -> u64 {
+    LEAKED_TEARDOWNS.load(Ordering::Relaxed)
 }
 
 #[derive(Copy, Clone)]
 pub struct QueueToken {
-    task_id: TaskHandle,
+    pub(crate) task_id: TaskHandle,
     pub(crate) op: QueueTokenOp,
+    pub(crate) tag: Option<u64>,
 }
 
-#[derive(Copy, Clone)]
+impl QueueToken {
+    /// The tag passed to `push_tagged`/`pop_tagged`, if any. Handy for joining completions back
+    /// to application-level request ids.
+    pub fn tag(&self) -> Option<u64> {
+        self.tag
+    }
+
+    /// The push's work id, unique per connection. `None` for pops, which aren't tied to any
+    /// particular receive.
+    pub fn work_id(&self) -> Option<u64> {
+        match self.op {
+            QueueTokenOp::Push { work_id } => Some(work_id),
+            QueueTokenOp::Pop => None,
+        }
+    }
+
+    /// The connection this token belongs to.
+    pub fn task(&self) -> TaskHandle {
+        self.task_id
+    }
+}
+
+impl fmt::Debug for QueueToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("QueueToken");
+        debug.field("task", &self.task_id.0);
+        match self.op {
+            QueueTokenOp::Push { work_id } => debug.field("push", &work_id),
+            QueueTokenOp::Pop => debug.field("pop", &()),
+        };
+        debug.field("tag", &self.tag).finish()
+    }
+}
+
+impl fmt::Display for QueueToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.op {
+            QueueTokenOp::Push { work_id } => write!(f, "push {} on {}", work_id, self.task_id)?,
+            QueueTokenOp::Pop => write!(f, "pop on {}", self.task_id)?,
+        }
+        match self.tag {
+            Some(tag) => write!(f, " (tag {})", tag),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
 pub enum QueueTokenOp {
     Push { work_id: u64 },
     Pop,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct TaskHandle(usize);
+/// Identifies a connection within its `IoQueue`, see `ConnectionStats::slot`. The second field
+/// is the `IoQueue` instance the connection belongs to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct TaskHandle(usize, u64);
+
+impl TaskHandle {
+    pub fn slot(&self) -> usize {
+        self.0
+    }
+}
+
+impl fmt::Display for TaskHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection {}", self.0)
+    }
+}
+
+/// Where an outstanding token is stuck, see `IoQueue::pending_report`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TokenStatus {
+    /// The push hasn't been posted yet. `queued` pushes of this connection are in the push
+    /// queue in total. Tokens already waited on look like this too.
+    Queued { queued: usize },
+    /// Like `Queued`, but the peer has advertised no send windows, so nothing is posted until
+    /// it pops some of what we already sent.
+    FlowControlBlocked { queued: usize },
+    /// Posted to the NIC, waiting for its completion.
+    Posted,
+    /// Done. The next `wait`/`try_wait` returns it.
+    Completed,
+    /// A pop with nothing received so far.
+    AwaitingData,
+    /// The connection failed, waiting returns this error.
+    Failed(IoQueueError),
+    /// The connection has been shut down.
+    Closed,
+}
+
+/// Which operation a `CompletedRequest` is the result of.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RequestKind {
+    Push,
+    Pop,
+}
 
 pub enum CompletedRequest<T, const SIZE: usize> {
-    Pop(RdmaMemory<T, SIZE>),
+    /// The received buffer and how many bytes arrived in it, i.e. `memory.len()`.
+    Pop(RdmaMemory<T, SIZE>, usize),
     Push(RdmaMemory<T, SIZE>),
+    /// A `push_copy` completed. Its buffer already went back to the pool.
+    Sent,
+    /// The connection died before this request completed.
+    Error(IoQueueError),
+    /// A pop whose payload didn't match its checksum. Only produced with
+    /// `IoQueueConfig::checksum` set. `memory` holds the (corrupt) payload.
+    Corrupt {
+        memory: RdmaMemory<T, SIZE>,
+        expected: u32,
+        actual: u32,
+    },
+    /// The peer called `shutdown_send` and everything it sent before has been popped. Every
+    /// further pop on this connection returns this too.
+    EndOfStream,
+    /// A doorbell the peer sent with `notify`. Only produced with
+    /// `IoQueueConfig::notifications` set.
+    Notify,
+}
+
+/// What `IoQueue::closed` resolves to.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The peer called `shutdown_send` and everything it sent before has been popped.
+    PeerShutdown,
+    /// The connection failed or was torn down, with the error its tokens resolve to, e.g.
+    /// `PeerUnresponsive` from the keepalive probe or `ConnectionClosed` after `disconnect`.
+    Failed(IoQueueError),
 }
 
 impl<T, const SIZE: usize> CompletedRequest<T, SIZE> {
+    /// Whether this completed a push or a pop, so results from `wait_any` can be matched on
+    /// without tracking the original token. `None` for `Error`, which can come from either.
+    pub fn kind(&self) -> Option<RequestKind> {
+        match self {
+            CompletedRequest::Push(_) | CompletedRequest::Sent => Some(RequestKind::Push),
+            CompletedRequest::Pop(..)
+            | CompletedRequest::Corrupt { .. }
+            | CompletedRequest::EndOfStream
+            | CompletedRequest::Notify => Some(RequestKind::Pop),
+            CompletedRequest::Error(_) => None,
+        }
+    }
+
+    /// The popped memory, or `self` back if this isn't a successful pop.
+    pub fn try_pop_op(self) -> Result<RdmaMemory<T, SIZE>, Self> {
+        match self {
+            CompletedRequest::Pop(memory, _) => Ok(memory),
+            other => Err(other),
+        }
+    }
+
+    /// The pushed memory, or `self` back if this isn't a successful push.
+    pub fn try_push_op(self) -> Result<RdmaMemory<T, SIZE>, Self> {
+        match self {
+            CompletedRequest::Push(memory) => Ok(memory),
+            other => Err(other),
+        }
+    }
+
+    /// Panics unless this is a successful pop. See `try_pop_op`.
     pub fn pop_op(self) -> RdmaMemory<T, SIZE> {
         match self {
-            CompletedRequest::Pop(memory) => memory,
-            CompletedRequest::Push(_) => panic!("Push event instead of pop."),
+            CompletedRequest::Pop(memory, _) => memory,
+            CompletedRequest::Push(_) | CompletedRequest::Sent => {
+                panic!("Push event instead of pop.")
+            }
+            CompletedRequest::Error(e) => panic!("Pop failed: {}", e),
+            CompletedRequest::Corrupt {
+                expected, actual, ..
+            } => panic!(
+                "Pop checksum mismatch: expected {:#010x}, got {:#010x}.",
+                expected, actual
+            ),
+            CompletedRequest::EndOfStream => panic!("Peer shut down its send direction."),
+            CompletedRequest::Notify => panic!("Doorbell instead of pop."),
         }
     }
 
+    /// Panics unless this is a successful push. See `try_push_op`.
     pub fn push_op(self) -> RdmaMemory<T, SIZE> {
         match self {
-            CompletedRequest::Pop(_memory) => panic!("Push event instead of push."),
+            CompletedRequest::Pop(..) => panic!("Push event instead of push."),
             CompletedRequest::Push(memory) => memory,
+            CompletedRequest::Sent => panic!("Push made with push_copy has no memory to return."),
+            CompletedRequest::Error(e) => panic!("Push failed: {}", e),
+            CompletedRequest::Corrupt { .. } => panic!("Corrupt event instead of push."),
+            CompletedRequest::EndOfStream => panic!("End of stream event instead of push."),
+            CompletedRequest::Notify => panic!("Doorbell instead of push."),
         }
     }
 }
 
-thread_local! {
-    pub static TIME: RefCell<u32> = RefCell::new(0);
+/// Where a connection has spent its time since it was established or since the last
+/// `reset_timing`.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ConnectionTiming {
+    /// Time spent polling `push_coroutine`: draining the push queue, waiting on send windows
+    /// and posting sends to the NIC.
+    pub push: Duration,
+    /// Time spent polling `completions_coroutine`: polling the completion queue and handing
+    /// completed work back to its pushes and pops.
+    pub completions: Duration,
+    /// Wall-clock time callers spent blocked in `wait`/`wait_any` until one of this connection's
+    /// tokens completed. This includes any of the time above spent while waiting.
+    pub waiting: Duration,
+}
+
+/// A live connection as listed by `IoQueue::connections`.
+#[derive(Debug, Clone)]
+pub struct ConnectionRef<'a> {
+    pub handle: TaskHandle,
+    /// The `node:service` or socket address passed to `connect`. rdma-cm doesn't report who
+    /// connected to us, so this is `None` for accepted connections.
+    pub peer_address: Option<&'a str>,
+    pub stats: ConnectionStats,
 }
 
-// TODO: Currently we must make sure the protection domain is declared last as we need to deallocate
-// all other registered memory before deallocating protection domain. How to fix this?
+/// Counters about what the NIC has reported for a connection.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ConnectionStats {
+    /// Completions that failed because the peer had no receive buffer posted and the RNR retry
+    /// count ran out.
+    pub rnr_retry_errors: u64,
+    /// Completions that failed because the transport retry count ran out, i.e. the peer never
+    /// acknowledged. On a lossy fabric this usually means congestion.
+    pub retry_errors: u64,
+    /// Receive buffers posted so far, including the ones posted during connection setup.
+    pub receives_posted: u64,
+    /// Where this connection's state lives in the executor. Connections get consecutive slots in
+    /// the order they were established.
+    pub slot: usize,
+    /// Core requested with `IoQueue::set_core_affinity`. Only recorded for now: every coroutine
+    /// still runs on whichever thread polls the `IoQueue`.
+    pub core_affinity: Option<usize>,
+    /// Most sends `push_coroutine` posts per pass, set with `IoQueue::set_send_batch`. `None`
+    /// means only the available send windows limit it.
+    pub send_batch: Option<usize>,
+    /// Receive buffers posted at once whenever fewer than half of the receive windows are left.
+    pub recv_batch: usize,
+    /// Times the memory pool ran dry while posting receive buffers. Receives are posted again
+    /// once buffers are freed, but a growing count means the pool is too small.
+    pub pool_exhausted: u64,
+    /// Successful completions by opcode. Failed completions don't say what they were for and
+    /// only show up in the error counters above.
+    pub recv_completions: u64,
+    pub send_completions: u64,
+    /// Includes window updates and keepalive probes.
+    pub rdma_write_completions: u64,
+    pub rdma_read_completions: u64,
+    /// Compare-and-swap and fetch-and-add.
+    pub atomic_completions: u64,
+}
+
+impl ConnectionStats {
+    pub fn congestion_events(&self) -> u64 {
+        self.rnr_retry_errors + self.retry_errors
+    }
+}
+
+/// Called with the connection's stats once `congestion_events` reaches the threshold.
+pub type CongestionHook = Box<dyn FnMut(ConnectionStats)>;
+
+/// Registered with `pop_with_callback`. Returns the buffer to have it recycled right away, or
+/// `None` if it kept the buffer, which must then be `free`d like any other popped buffer.
+pub type PopCallback<const SIZE: usize> =
+    Box<dyn FnOnce(CompletedRequest<u8, SIZE>) -> Option<RdmaMemory<u8, SIZE>>>;
+
+/// Called with the connection's stats every time `pool_exhausted` goes up.
+pub type PoolExhaustedHook = Box<dyn FnMut(ConnectionStats)>;
+
+/// Bookkeeping shared between a `ConnectionTask` and its coroutines. Holds no verbs objects,
+/// so when it is dropped doesn't matter for teardown.
+struct ConnectionShared<const BUFFER_SIZE: usize> {
+    completed_pops: RefCell<Vec<RdmaMemory<u8, BUFFER_SIZE>>>,
+    completed_pushes: RefCell<HashMap<u64, RdmaMemory<u8, BUFFER_SIZE>>>,
+    /// Served before `completed_pops` is left for `wait`, oldest first.
+    pop_callbacks: RefCell<VecDeque<PopCallback<BUFFER_SIZE>>>,
+    work_id_counter: RefCell<u64>,
+    /// Work ids of pushes cancelled before `push_coroutine` posted them. It recycles their
+    /// memory instead of sending it.
+    cancelled_pushes: RefCell<HashSet<u64>>,
+    /// Work ids of `push_copy` pushes still in flight. `completions_coroutine` recycles their
+    /// memory as soon as they complete and, if the value is true, records them in
+    /// `completed_copies` for their token. Untracked copies have no token to wait on.
+    copied_pushes: RefCell<HashMap<u64, bool>>,
+    /// Work ids of `write_copy` writes still in flight, tracked the same way as
+    /// `copied_pushes`. Their buffers sit in `processed_requests` like those of posted sends.
+    posted_writes: RefCell<HashMap<u64, bool>>,
+    completed_copies: RefCell<HashSet<u64>>,
+    /// Work ids of pushes handed to `push_coroutine` that it hasn't posted (or dropped as
+    /// cancelled) yet.
+    unposted: RefCell<HashSet<u64>>,
+    processed_requests: PostedRequests<BUFFER_SIZE>,
+    /// Set once the connection is known to be dead. Every outstanding and future token on
+    /// this connection resolves to this error.
+    connection_error: Cell<Option<IoQueueError>>,
+    /// When the completions coroutine last saw anything complete. The keepalive coroutine uses
+    /// this to tell idle connections from busy ones.
+    last_activity: Cell<Instant>,
+    stats: Cell<ConnectionStats>,
+    congestion_hook: RefCell<Option<(u64, CongestionHook)>>,
+    pool_exhausted_hook: RefCell<Option<PoolExhaustedHook>>,
+    rate_limit: SharedRateLimit,
+    send_batch: Cell<Option<usize>>,
+    /// Only present with `IoQueueConfig::latency_histograms`.
+    latency: Option<RefCell<ConnectionLatency>>,
+    /// Set once the peer's end-of-stream marker arrives.
+    peer_send_closed: Cell<bool>,
+    /// Doorbells from the peer's `notify` no pop has returned yet.
+    notifications: Cell<u64>,
+}
+
+impl<const BUFFER_SIZE: usize> ConnectionShared<BUFFER_SIZE> {
+    /// Posted sends and writes the NIC may still be reading from, i.e. what takes up room on the
+    /// send queue. Posted receive buffers only complete once the peer sends something, so they
+    /// don't count; `receives` is how many of those are posted.
+    fn in_flight_sends(&self, receives: u64) -> usize {
+        let posted = self.processed_requests.borrow().len();
+        if self.connection_error.get().is_some() {
+            // Everything still posted gets flushed, receives included.
+            return posted;
+        }
+        posted.saturating_sub(receives as usize)
+    }
+}
+
+/// Field order doesn't matter for teardown, see the `Drop` impl.
 struct ConnectionTask<
     const RECV_WRS: usize,
     const SEND_WRS: usize,
+    const CQ_ELEMENTS: usize,
     const WINDOW_SIZE: usize,
     const BUFFER_SIZE: usize,
 > {
-    memory_pool: Rc<RefCell<VecDeque<RdmaMemory<u8, BUFFER_SIZE>>>>,
-
-    recv_buffers_coroutine: Pin<Box<dyn Future<Output = ()>>>,
-    push_coroutine: Pin<Box<dyn Future<Output = ()>>>,
-    push_work_sender: async_channel::Sender<WorkRequest<BUFFER_SIZE>>,
-    completed_pops: Rc<RefCell<Vec<RdmaMemory<u8, BUFFER_SIZE>>>>,
-    completed_pushes: Rc<RefCell<HashMap<u64, RdmaMemory<u8, BUFFER_SIZE>>>>,
-    work_id_counter: Rc<RefCell<u64>>,
-    control_flow: Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>,
-    completions_coroutine: Pin<Box<dyn Future<Output = ()>>>,
-    /// We keep the protection domain around to make sure it doesn't get dropped before
-    /// everything else.
-    _protection_domain: ProtectionDomain,
+    recv_buffers_coroutine: ManuallyDrop<Pin<Box<dyn Future<Output = ()>>>>,
+    push_coroutine: ManuallyDrop<Pin<Box<dyn Future<Output = ()>>>>,
+    push_work_sender: ManuallyDrop<async_channel::Sender<WorkRequest<BUFFER_SIZE>>>,
+    shared: Rc<ConnectionShared<BUFFER_SIZE>>,
+    /// User tags of pushes that haven't been handed back yet, by work id. Only needed to rebuild
+    /// their tokens in `drain_completed`.
+    push_tags: HashMap<u64, u64>,
+    /// Work ids of cancelled pushes whose tokens haven't resolved to `Cancelled` yet.
+    cancelled: HashSet<u64>,
+    control_flow: ManuallyDrop<Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>>,
+    /// Not used by the crate itself, only handed out by `raw_queue_pair`.
+    queue_pair: ManuallyDrop<QueuePair<RECV_WRS, SEND_WRS>>,
+    /// Only present when keepalive is enabled in the `IoQueueConfig`. Holds on to
+    /// `control_flow`.
+    keepalive_coroutine: Option<Pin<Box<dyn Future<Output = ()>>>>,
+    completions_coroutine: ManuallyDrop<Pin<Box<dyn Future<Output = ()>>>>,
+    /// Shared with `completions_coroutine`, so `quiesce` can still drain it once the coroutine
+    /// is shut down.
+    completion_queue: ManuallyDrop<SharedCompletionQueue<CQ_ELEMENTS>>,
+    timing: ConnectionTiming,
+    /// `poll_all_tasks` only polls this connection on one of every `poll_every` passes.
+    poll_every: usize,
+    /// Passes of `poll_all_tasks` since this connection was last polled.
+    passes_skipped: usize,
+    /// Once set every coroutine finishes the next time it is polled.
+    shutdown: Rc<Cell<bool>>,
+    /// Set by `shutdown_send`. Further pushes fail with `SendShutdown`.
+    send_closed: bool,
+    /// See `IoQueueConfig::teardown_drain_timeout`.
+    drain_timeout: Duration,
+    /// Only known for connections we initiated, see `ConnectionRef::peer_address`.
+    peer_address: Option<String>,
+    /// The CM id the connection was established on, shared with its `QueueDescriptor`. Dropped
+    /// after the verbs objects created from it.
+    cm: Rc<CommunicationManager>,
+    /// The protection domain and the memory pool registered with it. Either may be shared with
+    /// other connections.
+    resources: ManuallyDrop<ConnectionResources<BUFFER_SIZE>>,
+}
+
+impl<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    /// See `ConnectionShared::in_flight_sends`.
+    fn in_flight_sends(&self) -> usize {
+        let receives = self.control_flow.borrow().remaining_receive_windows();
+        self.shared.in_flight_sends(receives)
+    }
+
+    /// Poll the cq until the NIC is done with every posted send, giving up after
+    /// `drain_timeout`. Returns whether it got there. Polls the cq itself rather than
+    /// `completions_coroutine`, which stops once the connection is shut down. Nobody is left to
+    /// hand completions to at this point, so their buffers just go back to the pool.
+    fn quiesce(&mut self) -> bool {
+        let start = Instant::now();
+        while self.in_flight_sends() > 0 {
+            if start.elapsed() >= self.drain_timeout {
+                return false;
+            }
+            let completed = match self.completion_queue.borrow_mut().poll_batch() {
+                Some(completed) => completed,
+                None => continue,
+            };
+            let mut processed_requests = self.shared.processed_requests.borrow_mut();
+            let mut memory_pool = self.resources.memory_pool.borrow_mut();
+            let mut receives = 0;
+            for c in completed {
+                if c.status != rdma_cm::ffi::ibv_wc_status_IBV_WC_SUCCESS {
+                    if self.shared.connection_error.get().is_none() {
+                        self.shared
+                            .connection_error
+                            .set(Some(IoQueueError::WorkCompletion(c.status)));
+                    }
+                } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RECV {
+                    receives += 1;
+                }
+                // Keepalives and window updates have no buffer.
+                if let Some((mut memory, _)) = processed_requests.remove(&c.wr_id) {
+                    memory.reset_access();
+                    memory_pool.recycle(memory);
+                }
+            }
+            // Keeps `in_flight_sends` from counting the receives we just took as sends.
+            self.control_flow
+                .borrow_mut()
+                .subtract_recv_windows(receives);
+        }
+        true
+    }
+}
+
+/// Tears a connection down in the order its verbs objects depend on each other: the queue pair,
+/// the completion queue, buffers held by the connection (recycled into the pool) and finally the
+/// memory pool and protection domain. Sends still in flight are drained first.
+impl<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Drop for ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    fn drop(&mut self) {
+        let quiesced = self.quiesce();
+        if !quiesced {
+            LEAKED_TEARDOWNS.fetch_add(1, Ordering::Relaxed);
+            error!(
+                "Tearing down a connection with {} sends still in flight. Leaking its registered \
+                 memory so the NIC can't write into freed buffers. Wait for every token before \
+                 shutting down.",
+                self.in_flight_sends()
+            );
+        }
+
+        // Every remaining holder of the queue pair. The push coroutine owns the receiving end of
+        // the push queue, so dropping the sender after it drops any queued pushes.
+        // Safety: none of the fields dropped here are touched again.
+        unsafe { ManuallyDrop::drop(&mut self.control_flow) };
+        unsafe { ManuallyDrop::drop(&mut self.queue_pair) };
+        self.keepalive_coroutine = None;
+        unsafe { ManuallyDrop::drop(&mut self.push_coroutine) };
+        unsafe { ManuallyDrop::drop(&mut self.recv_buffers_coroutine) };
+        unsafe { ManuallyDrop::drop(&mut self.push_work_sender) };
+        // Drops its own `control_flow` clone, the last one, before the cq.
+        unsafe { ManuallyDrop::drop(&mut self.completions_coroutine) };
+        unsafe { ManuallyDrop::drop(&mut self.completion_queue) };
+
+        // Completed buffers are done with either way. With `shared_accept_pool` the pool outlives
+        // this connection, so everything has to go back to it rather than be dropped.
+        let mut memory_pool = self.resources.memory_pool.borrow_mut();
+        let mut completed: Vec<_> = self.shared.completed_pops.borrow_mut().drain(..).collect();
+        completed.extend(
+            self.shared
+                .completed_pushes
+                .borrow_mut()
+                .drain()
+                .map(|(_, m)| m),
+        );
+        for mut memory in completed {
+            memory.reset_access();
+            memory_pool.recycle(memory);
+        }
+        if quiesced {
+            let posted = self.shared.processed_requests.borrow_mut().take_all();
+            for (mut memory, _) in posted {
+                memory.reset_access();
+                memory_pool.recycle(memory);
+            }
+            drop(memory_pool);
+            // Safety: `resources` is never touched again.
+            unsafe { ManuallyDrop::drop(&mut self.resources) };
+        } else {
+            drop(memory_pool);
+            let posted = std::mem::replace(
+                &mut *self.shared.processed_requests.borrow_mut(),
+                WorkSlots::new(0),
+            );
+            std::mem::forget(posted);
+        }
+    }
 }
 
 impl<
@@ -103,7 +586,9 @@ impl<
         const BUFFER_SIZE: usize,
     > Executor<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
 {
-    pub fn new() -> Executor<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
+    pub fn new(
+        config: IoQueueConfig,
+    ) -> Executor<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
         info!(
             "{} with N={} and Size={}",
             function_name!(),
@@ -112,222 +597,1340 @@ impl<
         );
         Executor {
             tasks: Vec::with_capacity(100),
+            config,
+            pool_factory: memory_pool::default_factory(),
+            accept_resources: Vec::new(),
+            instance: NEXT_INSTANCE.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Only affects connections established after this call.
+    pub fn set_memory_pool_factory(&mut self, factory: MemoryPoolFactory<BUFFER_SIZE>) {
+        self.pool_factory = factory;
+    }
+
+    pub fn config(&self) -> &IoQueueConfig {
+        &self.config
+    }
+
+    /// Build a memory pool in `protection_domain` big enough for `connections` connections.
+    pub fn new_resources(
+        &self,
+        mut protection_domain: ProtectionDomain,
+        connections: usize,
+    ) -> ConnectionResources<BUFFER_SIZE> {
+        // Over-provision so a full receive window can be posted with buffers left for pushes.
+        let pool_size = self.config.pool_overprovision * WINDOW_SIZE * connections;
+        info!(
+            "Allocating memory pool of {} buffers ({}x window of {} for {} connection(s)).",
+            pool_size, self.config.pool_overprovision, WINDOW_SIZE, connections
+        );
+        let mut memory_pool = (self.pool_factory)(&mut protection_domain, pool_size);
+        if self.config.prefault_pool {
+            memory_pool::prefault(memory_pool.as_mut());
+        }
+        if self.config.lock_memory {
+            if let Err(e) = mlockall(MlockAllFlags::MCL_CURRENT) {
+                warn!("Unable to lock memory: {}. Is RLIMIT_MEMLOCK too low?", e);
+            }
+        }
+        ConnectionResources::new(protection_domain, memory_pool)
+    }
+
+    /// Keep `resources` around for later connections from the same listener. Returns the index
+    /// to look them up with `accept_resources`.
+    pub fn share_accept_resources(&mut self, resources: ConnectionResources<BUFFER_SIZE>) -> usize {
+        self.accept_resources.push(resources);
+        self.accept_resources.len() - 1
+    }
+
+    pub fn accept_resources(&self, index: usize) -> ConnectionResources<BUFFER_SIZE> {
+        self.accept_resources[index].clone()
+    }
+
+    pub fn add_new_connection(
+        &mut self,
+        mut control_flow: ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>,
+        queue_pair: QueuePair<RECV_WRS, SEND_WRS>,
+        resources: ConnectionResources<BUFFER_SIZE>,
+        completion_queue: CompletionQueue<CQ_ELEMENTS>,
+        cm: Rc<CommunicationManager>,
+        peer_address: Option<String>,
+    ) -> TaskHandle {
+        info!("{}", function_name!());
+
+        let push_queue_capacity = self.config.push_queue_capacity.unwrap_or(WINDOW_SIZE);
+        // A batch of 0 would never hand anything out.
+        let cq_poll_batch = self
+            .config
+            .cq_poll_batch
+            .unwrap_or(CQ_ELEMENTS)
+            .min(CQ_ELEMENTS)
+            .max(1);
+        let (push_work_sender, push_work_receiver) =
+            async_channel::bounded::<WorkRequest<BUFFER_SIZE>>(push_queue_capacity);
+
+        let memory_pool = resources.memory_pool.clone();
+        control_flow.set_update_coalescing(
+            self.config.window_update_threshold,
+            self.config.window_update_delay,
+        );
+        let control_flow = Rc::new(RefCell::new(control_flow));
+        let completion_queue = Rc::new(RefCell::new(AsyncCompletionQueue {
+            cq: completion_queue,
+            batch_size: cq_poll_batch,
+            pending: None,
+        }));
+        let shared = Rc::new(ConnectionShared {
+            completed_pops: RefCell::new(Vec::with_capacity(1000)),
+            completed_pushes: RefCell::new(HashMap::with_capacity(1000)),
+            pop_callbacks: RefCell::new(VecDeque::new()),
+            work_id_counter: RefCell::new(0),
+            cancelled_pushes: RefCell::new(HashSet::new()),
+            copied_pushes: RefCell::new(HashMap::new()),
+            posted_writes: RefCell::new(HashMap::new()),
+            completed_copies: RefCell::new(HashSet::new()),
+            unposted: RefCell::new(HashSet::new()),
+            processed_requests: RefCell::new(WorkSlots::new(self.config.work_slots.unwrap_or(0))),
+            connection_error: Cell::new(None),
+            last_activity: Cell::new(Instant::now()),
+            stats: Cell::new(ConnectionStats {
+                slot: self.tasks.len(),
+                recv_batch: WINDOW_SIZE,
+                ..ConnectionStats::default()
+            }),
+            congestion_hook: RefCell::new(None),
+            pool_exhausted_hook: RefCell::new(None),
+            rate_limit: Rc::new(RefCell::new(None)),
+            send_batch: Cell::new(None),
+            latency: if self.config.latency_histograms {
+                Some(RefCell::new(ConnectionLatency::new()))
+            } else {
+                None
+            },
+            peer_send_closed: Cell::new(false),
+            notifications: Cell::new(0),
+        });
+        let shutdown = Rc::new(Cell::new(false));
+
+        let keepalive_coroutine = self.config.keepalive_interval.map(|interval| {
+            until_shutdown(
+                &shutdown,
+                keepalive_coroutine(
+                    control_flow.clone(),
+                    interval,
+                    self.config.keepalive_timeout,
+                    shared.clone(),
+                ),
+            )
+        });
+
+        let mut ct = ConnectionTask {
+            resources: ManuallyDrop::new(resources),
+            push_coroutine: ManuallyDrop::new(until_shutdown(
+                &shutdown,
+                push_coroutine(
+                    queue_pair.clone(),
+                    push_work_receiver,
+                    control_flow.clone(),
+                    memory_pool.clone(),
+                    shared.clone(),
+                ),
+            )),
+            recv_buffers_coroutine: ManuallyDrop::new(until_shutdown(
+                &shutdown,
+                recv_buffers_coroutine(
+                    queue_pair.clone(),
+                    control_flow.clone(),
+                    memory_pool.clone(),
+                    shared.clone(),
+                    self.config.initial_recv_posts.unwrap_or(WINDOW_SIZE) as u64,
+                ),
+            )),
+            completions_coroutine: ManuallyDrop::new(until_shutdown(
+                &shutdown,
+                completions_coroutine(
+                    control_flow.clone(),
+                    completion_queue.clone(),
+                    memory_pool,
+                    shared.clone(),
+                    Framing {
+                        checksum: self.config.checksum,
+                        notifications: self.config.notifications,
+                    },
+                    TaskHandle(self.tasks.len(), self.instance),
+                ),
+            )),
+            completion_queue: ManuallyDrop::new(completion_queue),
+            shutdown,
+            send_closed: false,
+            keepalive_coroutine,
+            timing: ConnectionTiming::default(),
+            poll_every: 1,
+            passes_skipped: 0,
+            drain_timeout: self.config.teardown_drain_timeout,
+            peer_address,
+            cm,
+            control_flow: ManuallyDrop::new(control_flow),
+            queue_pair: ManuallyDrop::new(queue_pair),
+            push_work_sender: ManuallyDrop::new(push_work_sender),
+            shared,
+            push_tags: HashMap::new(),
+            cancelled: HashSet::new(),
+        };
+
+        Self::schedule(&mut ct.recv_buffers_coroutine);
+
+        let handle = TaskHandle(self.tasks.len(), self.instance);
+        self.tasks.push(Some(ct));
+        handle
+    }
+
+    pub fn apply_settings(&mut self, task: TaskHandle, settings: &ConnectionSettings) {
+        self.set_poll_every(task, settings.poll_every);
+        self.set_send_batch(task, settings.send_batch);
+        self.set_rate_limit(task, settings.rate_limit);
+        self.set_core_affinity(task, settings.core_affinity);
+    }
+
+    pub fn malloc(&mut self, task: TaskHandle) -> RdmaMemory<u8, BUFFER_SIZE> {
+        trace!("{}", function_name!());
+
+        let mut memory_pool = self.task_mut(task).resources.memory_pool.borrow_mut();
+        trace!(
+            "Malloc: Entries in memory pool: {}",
+            memory_pool.available()
+        );
+        memory_pool.allocate().expect("Out of memory!")
+    }
+
+    pub fn free(
+        &mut self,
+        task: TaskHandle,
+        mut memory: RdmaMemory<u8, BUFFER_SIZE>,
+    ) -> Result<(), FreeError<BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+
+        if !self.owns(task) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(FreeError {
+                error: IoQueueError::WrongInstance,
+                memory: vec![memory],
+            });
+        }
+        let task = self.task_mut(task);
+        let mut memory_pool = task.resources.memory_pool.borrow_mut();
+        if !memory_pool.owns(&memory) {
+            warn!("{}", FOREIGN_BUFFER_HINT);
+            return Err(FreeError {
+                error: IoQueueError::ForeignBuffer,
+                memory: vec![memory],
+            });
+        }
+        if memory_pool.is_pooled(&memory) {
+            warn!("Buffer freed twice, ignoring the second free.");
+            return Err(FreeError {
+                error: IoQueueError::DoubleFree,
+                memory: vec![memory],
+            });
+        }
+        trace!("Free: Entries in memory pool: {}", memory_pool.available());
+        memory.reset_access();
+        memory_pool.recycle(memory);
+        Ok(())
+    }
+
+    /// `free` for a whole batch under a single borrow of the pool. Every buffer is checked for
+    /// ownership before any of them is recycled, so a foreign one fails the whole batch. Buffers
+    /// that were already freed are handed back like in `free`, the rest still go into the pool.
+    pub fn free_all(
+        &mut self,
+        task: TaskHandle,
+        memory: Vec<RdmaMemory<u8, BUFFER_SIZE>>,
+    ) -> Result<(), FreeError<BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+
+        if !self.owns(task) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(FreeError {
+                error: IoQueueError::WrongInstance,
+                memory,
+            });
+        }
+        let task = self.task_mut(task);
+        let mut memory_pool = task.resources.memory_pool.borrow_mut();
+        if !memory.iter().all(|m| memory_pool.owns(m)) {
+            warn!("{}", FOREIGN_BUFFER_HINT);
+            return Err(FreeError {
+                error: IoQueueError::ForeignBuffer,
+                memory,
+            });
+        }
+        let mut already_freed = Vec::new();
+        for mut m in memory {
+            // Also catches the same buffer showing up twice in `memory`.
+            if memory_pool.is_pooled(&m) {
+                warn!("Buffer freed twice, ignoring the second free.");
+                already_freed.push(m);
+                continue;
+            }
+            m.reset_access();
+            memory_pool.recycle(m);
+        }
+        trace!("Free: Entries in memory pool: {}", memory_pool.available());
+        if already_freed.is_empty() {
+            Ok(())
+        } else {
+            Err(FreeError {
+                error: IoQueueError::DoubleFree,
+                memory: already_freed,
+            })
+        }
+    }
+
+    pub fn push(
+        &mut self,
+        task_handle: TaskHandle,
+        memory: RdmaMemory<u8, BUFFER_SIZE>,
+        tag: Option<u64>,
+    ) -> Result<QueueToken, PushError<BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+
+        let checksum = self.config.checksum;
+        let notifications = self.config.notifications;
+        if !self.owns(task_handle) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(PushError {
+                error: IoQueueError::WrongInstance,
+                memory,
+            });
+        }
+        let admitted = self.admit(task_handle);
+        let task = self.task_mut(task_handle);
+
+        if task.send_closed {
+            return Err(PushError {
+                error: IoQueueError::SendShutdown,
+                memory,
+            });
+        }
+        // Zero length sends are the end-of-stream marker, only `shutdown_send` may queue one.
+        if memory.accessed() == 0 {
+            return Err(PushError {
+                error: IoQueueError::EmptyPush,
+                memory,
+            });
+        }
+        if !task.resources.memory_pool.borrow().owns(&memory) {
+            warn!("{}", FOREIGN_BUFFER_HINT);
+            return Err(PushError {
+                error: IoQueueError::ForeignBuffer,
+                memory,
+            });
+        }
+        if let Err(error) = admitted {
+            return Err(PushError { error, memory });
+        }
+        let mut memory = memory;
+        if checksum && !checksum::prepend(&mut memory) {
+            return Err(PushError {
+                error: IoQueueError::MessageTooLarge,
+                memory,
+            });
+        }
+        if notifications && !notify::prepend(&mut memory, notify::DATA) {
+            if checksum {
+                let _ = checksum::strip(&mut memory);
+            }
+            return Err(PushError {
+                error: IoQueueError::MessageTooLarge,
+                memory,
+            });
+        }
+        Self::enqueue(task_handle, task, memory, tag).map_err(|mut e| {
+            // Hand back the buffer as the user filled it.
+            if notifications {
+                let _ = notify::strip(&mut e.memory);
+            }
+            if checksum {
+                let _ = checksum::strip(&mut e.memory);
+            }
+            e
+        })
+    }
+
+    /// Copy `parts` back to back into a fresh buffer and push it. The buffer is recycled as soon
+    /// as the send completes, waiting on the token returns `CompletedRequest::Sent`.
+    pub fn push_copy(
+        &mut self,
+        task_handle: TaskHandle,
+        parts: &[&[u8]],
+    ) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+        self.copy_and_push(task_handle, parts, true)
+    }
+
+    /// Like `push_copy` for callers that never wait on the push. Nothing is kept once the send
+    /// completes.
+    pub fn push_copy_untracked(
+        &mut self,
+        task_handle: TaskHandle,
+        parts: &[&[u8]],
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+        self.copy_and_push(task_handle, parts, false).map(|_| ())
+    }
+
+    fn copy_and_push(
+        &mut self,
+        task_handle: TaskHandle,
+        parts: &[&[u8]],
+        tracked: bool,
+    ) -> Result<QueueToken, IoQueueError> {
+        if !self.owns(task_handle) {
+            return Err(IoQueueError::WrongInstance);
+        }
+        let length: usize = parts.iter().map(|part| part.len()).sum();
+        if length == 0 {
+            return Err(IoQueueError::EmptyPush);
+        }
+        if length > BUFFER_SIZE {
+            return Err(IoQueueError::MessageTooLarge);
+        }
+        let mut memory = self.malloc(task_handle);
+        let buffer = memory.as_mut_slice(length);
+        let mut offset = 0;
+        for part in parts {
+            buffer[offset..offset + part.len()].copy_from_slice(part);
+            offset += part.len();
+        }
+        match self.push(task_handle, memory, None) {
+            Ok(qt) => {
+                if let QueueTokenOp::Push { work_id } = qt.op {
+                    let task = self.task(task_handle);
+                    task.shared
+                        .copied_pushes
+                        .borrow_mut()
+                        .insert(work_id, tracked);
+                }
+                Ok(qt)
+            }
+            Err(PushError { error, memory }) => {
+                self.free(task_handle, memory)?;
+                Err(error)
+            }
+        }
+    }
+
+    /// RDMA-write a copy of `data` to `addr` in the peer's memory, registered there under `rkey`.
+    /// The peer's CPU isn't involved: no receive window is used and nothing shows up on its pops.
+    /// The copy is recycled as soon as the write completes, waiting on the token returns
+    /// `CompletedRequest::Sent`.
+    pub fn write_copy(
+        &mut self,
+        task_handle: TaskHandle,
+        data: &[u8],
+        addr: u64,
+        rkey: u32,
+    ) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+        self.copy_and_write(task_handle, data, addr, rkey, true)
+    }
+
+    /// Like `write_copy` for callers that never wait on the write.
+    pub fn write_copy_untracked(
+        &mut self,
+        task_handle: TaskHandle,
+        data: &[u8],
+        addr: u64,
+        rkey: u32,
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+        self.copy_and_write(task_handle, data, addr, rkey, false)
+            .map(|_| ())
+    }
+
+    fn copy_and_write(
+        &mut self,
+        task_handle: TaskHandle,
+        data: &[u8],
+        addr: u64,
+        rkey: u32,
+        tracked: bool,
+    ) -> Result<QueueToken, IoQueueError> {
+        if !self.owns(task_handle) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(IoQueueError::WrongInstance);
+        }
+        if data.len() > BUFFER_SIZE {
+            return Err(IoQueueError::MessageTooLarge);
+        }
+        let task = self.task(task_handle);
+        if let Some(e) = task.shared.connection_error.get() {
+            return Err(e);
+        }
+        // Writes skip push_coroutine, which checks the same count before it posts anything, so
+        // between the two the send queue can't overrun.
+        if task.in_flight_sends() >= SEND_WRS {
+            return Err(IoQueueError::WouldBlock);
+        }
+        let task = self.task_mut(task_handle);
+        let allocated = task.resources.memory_pool.borrow_mut().allocate();
+        let mut memory = allocated.ok_or(IoQueueError::WouldBlock)?;
+        memory.as_mut_slice(data.len()).copy_from_slice(data);
+
+        let work_id = *task.shared.work_id_counter.borrow();
+        *task.shared.work_id_counter.borrow_mut() += 1;
+        let wr = [(work_id, memory)];
+        task.queue_pair
+            .post_send(wr.iter(), PostSendOpcode::RdmaWrite(addr, rkey));
+        let [(_, memory)] = wr;
+        task.shared
+            .processed_requests
+            .borrow_mut()
+            .insert(work_id, (memory, None));
+        task.shared
+            .posted_writes
+            .borrow_mut()
+            .insert(work_id, tracked);
+
+        Ok(QueueToken {
+            task_id: task_handle,
+            op: QueueTokenOp::Push { work_id },
+            tag: None,
+        })
+    }
+
+    /// Queue a doorbell: a send of nothing but the `notify::NOTIFY` header. Its buffer is
+    /// recycled as soon as the send completes, waiting on the token returns
+    /// `CompletedRequest::Sent`.
+    pub fn notify(&mut self, task_handle: TaskHandle) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+
+        if !self.owns(task_handle) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(IoQueueError::WrongInstance);
+        }
+        if !self.config.notifications {
+            return Err(IoQueueError::InvalidConfig(
+                "notify needs IoQueueConfig::notifications",
+            ));
+        }
+        if self.task(task_handle).send_closed {
+            return Err(IoQueueError::SendShutdown);
+        }
+        self.admit(task_handle)?;
+        let task = self.task_mut(task_handle);
+        let allocated = task.resources.memory_pool.borrow_mut().allocate();
+        let mut memory = allocated.ok_or(IoQueueError::WouldBlock)?;
+        memory.as_mut_slice(notify::KIND_SIZE)[0] = notify::NOTIFY;
+        match Self::enqueue(task_handle, task, memory, None) {
+            Ok(qt) => {
+                if let QueueTokenOp::Push { work_id } = qt.op {
+                    task.shared.copied_pushes.borrow_mut().insert(work_id, true);
+                }
+                Ok(qt)
+            }
+            Err(PushError { error, memory }) => {
+                task.resources.memory_pool.borrow_mut().recycle(memory);
+                Err(error)
+            }
+        }
+    }
+
+    /// Queue the end-of-stream marker, a zero length send, behind everything already pushed and
+    /// refuse any pushes after it. The marker skips the checksum so the peer can always tell it
+    /// apart from data.
+    pub fn shutdown_send(&mut self, task_handle: TaskHandle) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+
+        if !self.owns(task_handle) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(IoQueueError::WrongInstance);
+        }
+        if self.task(task_handle).send_closed {
+            return Err(IoQueueError::SendShutdown);
+        }
+        let mut memory = self.malloc(task_handle);
+        memory.reset_access();
+        let task = self.task_mut(task_handle);
+        match Self::enqueue(task_handle, task, memory, None) {
+            Ok(qt) => {
+                task.send_closed = true;
+                Ok(qt)
+            }
+            Err(PushError { error, memory }) => {
+                task.resources.memory_pool.borrow_mut().recycle(memory);
+                Err(error)
+            }
+        }
+    }
+
+    /// The limits every push has to pass before it's queued: `IoQueueConfig::max_outstanding`
+    /// and `IoQueueConfig::fail_without_send_windows`.
+    fn admit(&self, task: TaskHandle) -> Result<(), IoQueueError> {
+        let task = self.task(task);
+        if let Some(max) = self.config.max_outstanding {
+            if task.shared.processed_requests.borrow().len() + task.shared.unposted.borrow().len()
+                >= max
+            {
+                return Err(IoQueueError::WouldBlock);
+            }
+        }
+        if self.config.fail_without_send_windows {
+            let cf = task.control_flow.borrow();
+            // Windows granted but not yet picked up by push_coroutine count too. Everything
+            // already queued gets first pick.
+            let windows = cf.remaining_send_windows() + cf.other_side_recv_windows();
+            if windows <= task.shared.unposted.borrow().len() as u64 {
+                return Err(IoQueueError::NoSendWindows);
+            }
+        }
+        Ok(())
+    }
+
+    fn enqueue(
+        task_handle: TaskHandle,
+        task: &mut ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        memory: RdmaMemory<u8, BUFFER_SIZE>,
+        tag: Option<u64>,
+    ) -> Result<QueueToken, PushError<BUFFER_SIZE>> {
+        let work_id: u64 = task.shared.work_id_counter.borrow_mut().clone();
+        let work = WorkRequest {
+            memory,
+            work_id,
+            submitted: task.shared.latency.as_ref().map(|_| Instant::now()),
+        };
+
+        let mut sent = task.push_work_sender.try_send(work);
+        if let Err(async_channel::TrySendError::Full(work)) = sent {
+            // The peer may have advertised more windows since push_coroutine last ran. Let it
+            // drain what it can before giving up.
+            Self::schedule_timed(&mut task.push_coroutine, &mut task.timing.push);
+            sent = task.push_work_sender.try_send(work);
+        }
+        match sent {
+            Ok(()) => {}
+            Err(async_channel::TrySendError::Full(work)) => {
+                return Err(PushError {
+                    error: IoQueueError::WouldBlock,
+                    memory: work.memory,
+                })
+            }
+            // push_coroutine never returns, so this only happens if it panicked partway. Don't
+            // take the whole process down with it.
+            Err(async_channel::TrySendError::Closed(work)) => {
+                error!("Push queue for {:?} is closed.", task_handle);
+                return Err(PushError {
+                    error: IoQueueError::ConnectionClosed,
+                    memory: work.memory,
+                });
+            }
+        }
+        *task.shared.work_id_counter.borrow_mut() += 1;
+        task.shared.unposted.borrow_mut().insert(work_id);
+        if let Some(tag) = tag {
+            trace!("Push {} tagged {}.", work_id, tag);
+            task.push_tags.insert(work_id, tag);
+        }
+        Self::schedule_timed(&mut task.push_coroutine, &mut task.timing.push);
+
+        Ok(QueueToken {
+            task_id: task_handle,
+            op: QueueTokenOp::Push { work_id },
+            tag,
+        })
+    }
+
+    pub fn pop(&mut self, task_handle: TaskHandle, tag: Option<u64>) -> QueueToken {
+        trace!("{}", function_name!());
+
+        // Self::schedule(&mut task.recv_buffers_coroutine);
+        QueueToken {
+            task_id: task_handle,
+            op: QueueTokenOp::Pop,
+            tag,
+        }
+    }
+
+    /// Hand the next received buffer to `callback` from inside the completions coroutine
+    /// instead of to a `wait`. Callbacks are served in the order they were registered.
+    pub fn pop_with_callback(
+        &mut self,
+        task_handle: TaskHandle,
+        callback: PopCallback<BUFFER_SIZE>,
+    ) {
+        trace!("{}", function_name!());
+
+        self.task(task_handle)
+            .shared
+            .pop_callbacks
+            .borrow_mut()
+            .push_back(callback);
+    }
+
+    /// Cancel a push that hasn't been posted to the NIC yet; its memory goes back to the pool
+    /// and its token resolves to `Error(Cancelled)`. Pops don't reserve a receive buffer (any
+    /// completed receive satisfies any pop token) so cancelling one always succeeds and releases
+    /// nothing.
+    pub fn cancel(&mut self, qt: QueueToken) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+
+        if !self.owns(qt.task_id) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(IoQueueError::WrongInstance);
+        }
+        if !self.is_running(qt.task_id) {
+            return Err(IoQueueError::ConnectionClosed);
+        }
+        let task = self.task_mut(qt.task_id);
+        match qt.op {
+            QueueTokenOp::Push { work_id } => {
+                if task.cancelled.contains(&work_id) {
+                    return Err(IoQueueError::Cancelled);
+                }
+                // Once posted the NIC owns the memory until the send completes. Tokens that
+                // already completed aren't pending anymore either.
+                if !task.shared.unposted.borrow().contains(&work_id) {
+                    return Err(IoQueueError::AlreadyPosted);
+                }
+                task.shared.cancelled_pushes.borrow_mut().insert(work_id);
+                task.shared.copied_pushes.borrow_mut().remove(&work_id);
+                task.cancelled.insert(work_id);
+                Ok(())
+            }
+            QueueTokenOp::Pop => Ok(()),
+        }
+    }
+
+    fn task(
+        &self,
+        task: TaskHandle,
+    ) -> &ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
+        assert!(self.owns(task), "{}", WRONG_INSTANCE_HINT);
+        self.tasks
+            .get(task.0)
+            .and_then(Option::as_ref)
+            .expect(&format!("Missing task {:?}", task))
+    }
+
+    fn task_mut(
+        &mut self,
+        task: TaskHandle,
+    ) -> &mut ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
+        assert!(self.owns(task), "{}", WRONG_INSTANCE_HINT);
+        self.tasks
+            .get_mut(task.0)
+            .and_then(Option::as_mut)
+            .expect(&format!("Missing task {:?}", task))
+    }
+
+    /// Whether `task` is still running, i.e. hasn't been shut down.
+    pub fn is_running(&self, task: TaskHandle) -> bool {
+        self.owns(task) && matches!(self.tasks.get(task.0), Some(Some(_)))
+    }
+
+    /// Why `task`'s connection is over, or `None` while it is still usable. The peer's shutdown
+    /// only counts once every message it sent before has been popped.
+    pub fn disconnect_reason(&self, task: TaskHandle) -> Option<DisconnectReason> {
+        if let Some(e) = self.connection_error(task) {
+            return Some(DisconnectReason::Failed(e));
+        }
+        let shared = &self.task(task).shared;
+        if shared.peer_send_closed.get() && shared.completed_pops.borrow().is_empty() {
+            Some(DisconnectReason::PeerShutdown)
+        } else {
+            None
+        }
+    }
+
+    /// Stop all of `task`'s coroutines and release everything it holds. Its outstanding tokens
+    /// resolve to `ConnectionClosed`. Further calls with this handle, other than `wait`, panic.
+    pub fn shutdown(&mut self, task: TaskHandle) {
+        info!("{}: {:?}", function_name!(), task);
+
+        let t = self.task_mut(task);
+        t.shutdown.set(true);
+        // Every coroutine checks the flag first, so this pass is where they all finish.
+        let finished = Self::poll_task(t);
+        debug_assert!(finished, "Coroutines should finish once shut down.");
+        // Dropping the task drains in-flight sends and tears its state down, see the `Drop` impl
+        // of `ConnectionTask`. It leaks the connection's memory if they don't finish in time.
+        self.tasks[task.0] = None;
+    }
+
+    /// Disconnect and `shutdown` every running connection, then let go of the resources shared
+    /// by accepted connections, so every protection domain is released after the connections
+    /// using it.
+    pub fn shutdown_all(&mut self) {
+        info!("{}", function_name!());
+
+        for slot in 0..self.tasks.len() {
+            let task = TaskHandle(slot, self.instance);
+            if self.is_running(task) {
+                if let Err(e) = self.task(task).cm.disconnect() {
+                    warn!("Failed to disconnect {:?}: {:?}", task, e);
+                }
+                self.shutdown(task);
+            }
+        }
+        self.accept_resources.clear();
+    }
+
+    /// Returns true once the coroutine has finished, which only happens after a shutdown.
+    fn schedule(task: &mut Pin<Box<dyn Future<Output = ()>>>) -> bool {
+        trace!("{}", function_name!());
+
+        let waker = crate::waker::emtpy_waker();
+        task.as_mut()
+            .poll(&mut Context::from_waker(&waker))
+            .is_ready()
+    }
+
+    fn schedule_timed(task: &mut Pin<Box<dyn Future<Output = ()>>>, spent: &mut Duration) -> bool {
+        let start = Instant::now();
+        let finished = Self::schedule(task);
+        *spent += start.elapsed();
+        finished
+    }
+
+    /// Poll the coroutines associated with this QueueToken.
+    pub fn poll_coroutines(&mut self, qt: QueueToken) {
+        trace!("{}", function_name!());
+
+        if !self.is_running(qt.task_id) {
+            return;
+        }
+        if Self::poll_task(self.task_mut(qt.task_id)) {
+            self.tasks[qt.task_id.0] = None;
+        }
+    }
+
+    /// Whether `task` was handed out by this executor rather than another `IoQueue`'s.
+    pub fn owns(&self, task: TaskHandle) -> bool {
+        task.1 == self.instance
+    }
+
+    pub fn connection_error(&self, task: TaskHandle) -> Option<IoQueueError> {
+        if !self.owns(task) {
+            return Some(IoQueueError::WrongInstance);
+        }
+        match self.tasks.get(task.0) {
+            Some(Some(task)) => task.shared.connection_error.get(),
+            _ => Some(IoQueueError::ConnectionClosed),
+        }
+    }
+
+    /// Work requests posted to the NIC that haven't completed yet, sends and receives alike.
+    pub fn outstanding(&self, task: TaskHandle) -> usize {
+        self.task(task).shared.processed_requests.borrow().len()
+    }
+
+    /// Drive `task` until everything pushed so far has been posted to the NIC, or `timeout`
+    /// passes.
+    pub fn flush(
+        &mut self,
+        task_handle: TaskHandle,
+        timeout: Duration,
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+
+        if !self.owns(task_handle) {
+            warn!("{}", WRONG_INSTANCE_HINT);
+            return Err(IoQueueError::WrongInstance);
+        }
+        let start = Instant::now();
+        loop {
+            if !self.is_running(task_handle) {
+                return Err(IoQueueError::ConnectionClosed);
+            }
+            let task = self.task_mut(task_handle);
+            if let Some(e) = task.shared.connection_error.get() {
+                return Err(e);
+            }
+            let unposted = task.shared.unposted.borrow().len();
+            if unposted == 0 {
+                return Ok(());
+            }
+            if start.elapsed() >= timeout {
+                return Err(IoQueueError::FlushTimedOut { unposted });
+            }
+            if Self::poll_task(task) {
+                self.tasks[task_handle.0] = None;
+            }
+        }
+    }
+
+    /// Poll all tasks from all connections.
+    pub fn poll_all_tasks(&mut self) {
+        trace!("{}", function_name!());
+
+        for slot in self.tasks.iter_mut() {
+            if let Some(t) = slot {
+                t.passes_skipped += 1;
+                if t.passes_skipped < t.poll_every {
+                    continue;
+                }
+                t.passes_skipped = 0;
+                if Self::poll_task(t) {
+                    *slot = None;
+                }
+            }
+        }
+    }
+
+    pub fn poll_completion_coroutine(
+        &mut self,
+        qt: QueueToken,
+    ) -> Option<CompletedRequest<u8, BUFFER_SIZE>> {
+        if self.is_running(qt.task_id) {
+            let task = self.task_mut(qt.task_id);
+            Self::schedule_timed(
+                &mut task.completions_coroutine,
+                &mut task.timing.completions,
+            );
+        }
+        self.wait(qt)
+    }
+
+    /// Returns true if the task has finished and should be removed.
+    fn poll_task(
+        t: &mut ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+    ) -> bool {
+        let mut finished = Self::schedule_timed(&mut t.push_coroutine, &mut t.timing.push);
+
+        // Only schedule our recv buffers coroutine when receive window hits zero.
+        if t.shutdown.get()
+            || t.control_flow.borrow().remaining_receive_windows() < (WINDOW_SIZE / 2) as u64
+        {
+            // if t.control_flow.borrow().remaining_receive_windows() == 0 {
+            finished |= Self::schedule(&mut t.recv_buffers_coroutine);
+        }
+        finished |= Self::schedule_timed(&mut t.completions_coroutine, &mut t.timing.completions);
+        // Flushes window updates held back by coalescing once they are due.
+        let mut control_flow = t.control_flow.borrow_mut();
+        control_flow.maybe_advertise();
+        control_flow.check_credits();
+        drop(control_flow);
+        if let Some(keepalive) = t.keepalive_coroutine.as_mut() {
+            finished |= Self::schedule(keepalive);
         }
+        finished
     }
 
-    pub fn add_new_connection(
-        &mut self,
-        control_flow: ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>,
-        queue_pair: QueuePair<RECV_WRS, SEND_WRS>,
-        protection_domain: ProtectionDomain,
-        completion_queue: CompletionQueue<CQ_ELEMENTS>,
-    ) -> TaskHandle {
-        info!("{}", function_name!());
-
-        let (push_work_sender, push_work_receiver) =
-            async_channel::unbounded::<WorkRequest<BUFFER_SIZE>>();
-
-        let processed_requests = Rc::new(RefCell::new(HashMap::with_capacity(1000)));
-        let completed_pushes = Rc::new(RefCell::new(HashMap::with_capacity(1000)));
-        let completed_pops = Rc::new(RefCell::new(Vec::with_capacity(1000)));
+    pub fn timing(&self, task: TaskHandle) -> ConnectionTiming {
+        self.task(task).timing
+    }
 
-        // Allocate two times the amount of chunks we specify.
-        let memory_pool: VecDeque<RdmaMemory<u8, BUFFER_SIZE>> = protection_domain
-            .register_chunk(2 * WINDOW_SIZE)
-            .into_iter()
-            .collect();
+    pub fn reset_timing(&mut self, task: TaskHandle) {
+        self.task_mut(task).timing = ConnectionTiming::default();
+    }
 
-        let work_id_counter = Rc::new(RefCell::new(0));
-        let memory_pool = Rc::new(RefCell::new(memory_pool));
-        let control_flow = Rc::new(RefCell::new(control_flow));
+    /// Zero the counters in `ConnectionStats`, keeping the fields that describe the connection.
+    pub fn reset_stats(&mut self, task: TaskHandle) {
+        if let Some(latency) = &self.task(task).shared.latency {
+            let mut latency = latency.borrow_mut();
+            latency.push.reset();
+            latency.pop.reset();
+        }
+        let stats = &self.task(task).shared.stats;
+        let current = stats.get();
+        stats.set(ConnectionStats {
+            slot: current.slot,
+            core_affinity: current.core_affinity,
+            recv_batch: current.recv_batch,
+            ..ConnectionStats::default()
+        });
+    }
 
-        let mut ct = ConnectionTask {
-            memory_pool: memory_pool.clone(),
-            _protection_domain: protection_domain,
-            push_coroutine: Box::pin(push_coroutine(
-                queue_pair.clone(),
-                push_work_receiver,
-                control_flow.clone(),
-                processed_requests.clone(),
-            )),
-            recv_buffers_coroutine: Box::pin(recv_buffers_coroutine(
-                queue_pair,
-                control_flow.clone(),
-                memory_pool,
-                processed_requests.clone(),
-                work_id_counter.clone(),
-                // ready_pop_work_id,
-            )),
-            completions_coroutine: Box::pin(completions_coroutine(
-                control_flow.clone(),
-                completion_queue,
-                completed_pushes.clone(),
-                completed_pops.clone(),
-                processed_requests.clone(),
-            )),
-            control_flow,
-            push_work_sender,
-            completed_pops,
-            completed_pushes,
-            work_id_counter,
+    /// `None` unless `IoQueueConfig::latency_histograms` is set.
+    pub fn latency_percentiles(&self, task: TaskHandle, kind: RequestKind) -> Option<Percentiles> {
+        let latency = self.task(task).shared.latency.as_ref()?.borrow();
+        let histogram = match kind {
+            RequestKind::Push => &latency.push,
+            RequestKind::Pop => &latency.pop,
         };
+        Some(histogram.percentiles())
+    }
 
-        Self::schedule(&mut ct.recv_buffers_coroutine);
+    pub fn raw_queue_pair(&mut self, task: TaskHandle) -> &mut QueuePair<RECV_WRS, SEND_WRS> {
+        &mut self.task_mut(task).queue_pair
+    }
 
-        let current_task_id = self.tasks.len();
-        self.tasks.push(ct);
-        TaskHandle(current_task_id)
+    pub fn raw_protection_domain(&self, task: TaskHandle) -> RefMut<'_, ProtectionDomain> {
+        self.task(task).resources.protection_domain()
     }
 
-    pub fn malloc(&mut self, task: TaskHandle) -> RdmaMemory<u8, BUFFER_SIZE> {
-        trace!("{}", function_name!());
+    /// For memory that may outlive the connection: holding on to this keeps the protection
+    /// domain it is registered with alive.
+    pub fn protection_domain(&self, task: TaskHandle) -> Rc<RefCell<ProtectionDomain>> {
+        self.task(task).resources.shared_protection_domain()
+    }
 
-        let mut memory_pool = self
-            .tasks
-            .get_mut(task.0)
-            .expect(&format!("Missing task {:?}", task))
-            .memory_pool
-            .borrow_mut();
-        trace!("Malloc: Entries in memory pool: {}", memory_pool.len());
-        memory_pool.pop_front().expect("Out of memory!")
+    pub fn stats(&self, task: TaskHandle) -> ConnectionStats {
+        Self::task_stats(self.task(task))
     }
 
-    // TODO Make sure this buffer actually belongs to this handle?
-    pub fn free(&mut self, task: TaskHandle, mut memory: RdmaMemory<u8, BUFFER_SIZE>) {
-        trace!("{}", function_name!());
+    fn task_stats(
+        task: &ConnectionTask<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+    ) -> ConnectionStats {
+        ConnectionStats {
+            send_batch: task.shared.send_batch.get(),
+            ..task.shared.stats.get()
+        }
+    }
 
-        memory.reset_access();
-        let mut memory_pool = self
-            .tasks
-            .get_mut(task.0)
-            .expect(&format!("Missing task {:?}", task))
-            .memory_pool
-            .borrow_mut();
-        trace!("Free: Entries in memory pool: {}", memory_pool.len());
-        memory_pool.push_back(memory)
+    pub fn connections(&self) -> impl Iterator<Item = ConnectionRef<'_>> + '_ {
+        let instance = self.instance;
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter_map(move |(slot, task)| {
+                task.as_ref().map(|task| ConnectionRef {
+                    handle: TaskHandle(slot, instance),
+                    peer_address: task.peer_address.as_deref(),
+                    stats: Self::task_stats(task),
+                })
+            })
     }
 
-    pub fn push(
-        &mut self,
-        task_handle: TaskHandle,
-        memory: RdmaMemory<u8, BUFFER_SIZE>,
-    ) -> QueueToken {
-        let time = Instant::now();
-        trace!("{}", function_name!());
+    /// 0 is treated as 1, polling the task every pass.
+    pub fn set_poll_every(&mut self, task: TaskHandle, poll_every: usize) {
+        let task = self.task_mut(task);
+        task.poll_every = poll_every.max(1);
+        task.passes_skipped = 0;
+    }
 
-        let task: &mut ConnectionTask<RECV_WRS, SEND_WRS, WINDOW_SIZE, BUFFER_SIZE> =
-            self.tasks.get_mut(task_handle.0).unwrap();
+    /// A batch of 0 is treated as 1 so sends can't stall completely.
+    pub fn set_send_batch(&mut self, task: TaskHandle, batch: Option<usize>) {
+        self.task(task)
+            .shared
+            .send_batch
+            .set(batch.map(|n| n.max(1)));
+    }
 
-        let work_id: u64 = task.work_id_counter.borrow_mut().clone();
-        *task.work_id_counter.borrow_mut() += 1;
-        let work = WorkRequest { memory, work_id };
+    pub fn set_core_affinity(&mut self, task: TaskHandle, core: Option<usize>) {
+        let stats = &self.task(task).shared.stats;
+        let mut current = stats.get();
+        current.core_affinity = core;
+        stats.set(current);
+    }
 
-        task.push_work_sender
-            .try_send(work)
-            .expect("Channel should never be full or dropped.");
-        Self::schedule(&mut task.push_coroutine);
+    pub fn set_pool_exhausted_hook(&mut self, task: TaskHandle, hook: PoolExhaustedHook) {
+        *self.task_mut(task).shared.pool_exhausted_hook.borrow_mut() = Some(hook);
+    }
 
-        let elapsed = time.elapsed().as_nanos();
-        TIME.with(|time| *time.borrow_mut() += elapsed as u32);
-        QueueToken {
-            task_id: task_handle,
-            op: QueueTokenOp::Push { work_id },
-        }
+    pub fn set_congestion_hook(&mut self, task: TaskHandle, threshold: u64, hook: CongestionHook) {
+        *self.task_mut(task).shared.congestion_hook.borrow_mut() = Some((threshold, hook));
     }
 
-    pub fn pop(&mut self, task_handle: TaskHandle) -> QueueToken {
-        trace!("{}", function_name!());
+    pub fn set_rate_limit(&mut self, task: TaskHandle, bytes_per_sec: Option<u64>) {
+        *self.task_mut(task).shared.rate_limit.borrow_mut() =
+            bytes_per_sec.map(|rate| TokenBucket::new(rate, BUFFER_SIZE));
+    }
 
-        // Self::schedule(&mut task.recv_buffers_coroutine);
-        QueueToken {
-            task_id: task_handle,
-            op: QueueTokenOp::Pop,
+    /// Sends we could post right now. Like `SendWindows` we only look at what the peer has
+    /// advertised once our local count runs out.
+    pub fn send_windows(&self, task: TaskHandle) -> u64 {
+        let cf = self.task(task).control_flow.borrow();
+        match cf.remaining_send_windows() {
+            0 => cf.other_side_recv_windows(),
+            n => n,
         }
     }
 
-    fn schedule(task: &mut Pin<Box<dyn Future<Output = ()>>>) {
-        trace!("{}", function_name!());
+    /// Receive buffers currently posted and waiting for the peer to send into.
+    pub fn recv_windows(&self, task: TaskHandle) -> u64 {
+        self.task(task)
+            .control_flow
+            .borrow()
+            .remaining_receive_windows()
+    }
 
-        let waker = crate::waker::emtpy_waker();
-        if let Poll::Ready(_) = task.as_mut().poll(&mut Context::from_waker(&waker)) {
-            panic!("Our coroutines should never finish!")
-        }
+    pub fn last_activity(&self, task: TaskHandle) -> Instant {
+        self.task(task).shared.last_activity.get()
     }
 
-    /// Poll the coroutines associated with this QueueToken.
-    pub fn poll_coroutines(&mut self, qt: QueueToken) {
-        trace!("{}", function_name!());
+    /// True when we are out of send windows and the peer hasn't advertised any new ones, so
+    /// queued pushes can't go anywhere until it does.
+    pub fn send_windows_exhausted(&self, task: TaskHandle) -> bool {
+        let cf = self.task(task).control_flow.borrow();
+        cf.remaining_send_windows() == 0 && cf.other_side_recv_windows() == 0
+    }
 
-        let task: &mut ConnectionTask<RECV_WRS, SEND_WRS, WINDOW_SIZE, BUFFER_SIZE> =
-            self.tasks.get_mut(qt.task_id.0).unwrap();
+    /// Inspect `qt` without changing anything. Follows the same precedence as `wait`.
+    pub fn token_status(&self, qt: QueueToken) -> TokenStatus {
+        let task = match self.tasks.get(qt.task_id.0) {
+            Some(Some(task)) if self.owns(qt.task_id) => task,
+            _ => return TokenStatus::Closed,
+        };
+        match qt.op {
+            QueueTokenOp::Push { work_id } => {
+                if task.cancelled.contains(&work_id) {
+                    return TokenStatus::Failed(IoQueueError::Cancelled);
+                }
+                if task.shared.completed_pushes.borrow().contains_key(&work_id)
+                    || task.shared.completed_copies.borrow().contains(&work_id)
+                {
+                    return TokenStatus::Completed;
+                }
+                if let Some(e) = task.shared.connection_error.get() {
+                    return TokenStatus::Failed(e);
+                }
+                if task
+                    .shared
+                    .processed_requests
+                    .borrow()
+                    .contains_key(&work_id)
+                {
+                    return TokenStatus::Posted;
+                }
+                let queued = task.push_work_sender.len();
+                if self.send_windows_exhausted(qt.task_id) {
+                    TokenStatus::FlowControlBlocked { queued }
+                } else {
+                    TokenStatus::Queued { queued }
+                }
+            }
+            QueueTokenOp::Pop => {
+                if !task.shared.completed_pops.borrow().is_empty()
+                    || task.shared.notifications.get() > 0
+                    || task.shared.peer_send_closed.get()
+                {
+                    return TokenStatus::Completed;
+                }
+                match task.shared.connection_error.get() {
+                    Some(e) => TokenStatus::Failed(e),
+                    None => TokenStatus::AwaitingData,
+                }
+            }
+        }
+    }
 
-        Executor::<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>::poll_task(task);
+    /// Charge `waited` to the connection `qt` belongs to.
+    pub fn record_wait(&mut self, qt: QueueToken, waited: Duration) {
+        if !self.owns(qt.task_id) {
+            return;
+        }
+        if let Some(Some(task)) = self.tasks.get_mut(qt.task_id.0) {
+            task.timing.waiting += waited;
+        }
     }
 
-    /// Poll all tasks from all connections.
-    pub fn poll_all_tasks(&mut self) {
+    /// Checks if work corresponding to `qt` is finished returning the data. Otherwise returns
+    /// None. Once the connection has died every token resolves to `CompletedRequest::Error`.
+    pub fn wait(&mut self, qt: QueueToken) -> Option<CompletedRequest<u8, BUFFER_SIZE>> {
         trace!("{}", function_name!());
 
-        for t in self.tasks.iter_mut() {
-            Executor::<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>::poll_task(t);
+        let checksum = self.config.checksum;
+        if !self.owns(qt.task_id) {
+            return Some(CompletedRequest::Error(IoQueueError::WrongInstance));
         }
+        let task = match self.tasks.get_mut(qt.task_id.0) {
+            Some(Some(task)) => task,
+            _ => return Some(CompletedRequest::Error(IoQueueError::ConnectionClosed)),
+        };
+        let completed = match qt.op {
+            QueueTokenOp::Push { work_id } => {
+                let completed = task.shared.completed_pushes.borrow_mut().remove(&work_id);
+                match completed {
+                    Some(p) => {
+                        task.push_tags.remove(&work_id);
+                        Some(finish_push(checksum, p))
+                    }
+                    None if task.shared.completed_copies.borrow_mut().remove(&work_id) => {
+                        Some(CompletedRequest::Sent)
+                    }
+                    None if task.cancelled.remove(&work_id) => {
+                        task.push_tags.remove(&work_id);
+                        Some(CompletedRequest::Error(IoQueueError::Cancelled))
+                    }
+                    None => None,
+                }
+            }
+            // Just take the next ready one. It doesn't matter for pops.
+            QueueTokenOp::Pop => {
+                let popped = task.shared.completed_pops.borrow_mut().pop();
+                match popped {
+                    Some(p) => Some(finish_pop(checksum, qt.task_id, p)),
+                    None if take_notification(&task.shared.notifications) => {
+                        Some(CompletedRequest::Notify)
+                    }
+                    None if task.shared.peer_send_closed.get() => {
+                        Some(CompletedRequest::EndOfStream)
+                    }
+                    None => None,
+                }
+            }
+        };
+        completed.or_else(|| {
+            task.shared
+                .connection_error
+                .get()
+                .map(CompletedRequest::Error)
+        })
     }
 
-    pub fn poll_completion_coroutine(
+    /// Make one pass over `task` and take everything that has completed so far, each with a
+    /// token it could have been waited on with.
+    pub fn drain_completed(
         &mut self,
-        qt: QueueToken,
-    ) -> Option<CompletedRequest<u8, BUFFER_SIZE>> {
-        let task: &mut _ = self.tasks.get_mut(qt.task_id.0).unwrap();
-        Self::schedule(&mut task.completions_coroutine);
-        self.wait(qt)
-    }
+        task_handle: TaskHandle,
+    ) -> Vec<(QueueToken, CompletedRequest<u8, BUFFER_SIZE>)> {
+        trace!("{}", function_name!());
+
+        if !self.is_running(task_handle) {
+            return Vec::new();
+        }
+        let checksum = self.config.checksum;
+        let task = self.task_mut(task_handle);
+        if Self::poll_task(task) {
+            self.tasks[task_handle.0] = None;
+            return Vec::new();
+        }
 
-    fn poll_task(t: &mut ConnectionTask<RECV_WRS, SEND_WRS, WINDOW_SIZE, BUFFER_SIZE>) {
-        Self::schedule(&mut t.push_coroutine);
+        let push_tags = &mut task.push_tags;
+        let mut completed: Vec<_> = task
+            .shared
+            .completed_pushes
+            .borrow_mut()
+            .drain()
+            .map(|(work_id, p)| {
+                let qt = QueueToken {
+                    task_id: task_handle,
+                    op: QueueTokenOp::Push { work_id },
+                    tag: push_tags.remove(&work_id),
+                };
+                (qt, finish_push(checksum, p))
+            })
+            .collect();
+        completed.extend(
+            task.shared
+                .completed_copies
+                .borrow_mut()
+                .drain()
+                .map(|work_id| {
+                    let qt = QueueToken {
+                        task_id: task_handle,
+                        op: QueueTokenOp::Push { work_id },
+                        tag: None,
+                    };
+                    (qt, CompletedRequest::Sent)
+                }),
+        );
+        completed.extend(task.cancelled.drain().map(|work_id| {
+            let qt = QueueToken {
+                task_id: task_handle,
+                op: QueueTokenOp::Push { work_id },
+                tag: push_tags.remove(&work_id),
+            };
+            (qt, CompletedRequest::Error(IoQueueError::Cancelled))
+        }));
+        completed.extend(task.shared.completed_pops.borrow_mut().drain(..).map(|p| {
+            // Any pop token could have taken this buffer, so there is no tag to give back.
+            let qt = QueueToken {
+                task_id: task_handle,
+                op: QueueTokenOp::Pop,
+                tag: None,
+            };
+            (qt, finish_pop(checksum, task_handle, p))
+        }));
+        while take_notification(&task.shared.notifications) {
+            let qt = QueueToken {
+                task_id: task_handle,
+                op: QueueTokenOp::Pop,
+                tag: None,
+            };
+            completed.push((qt, CompletedRequest::Notify));
+        }
+        completed
+    }
+}
 
-        // Only schedule our recv buffers coroutine when receive window hits zero.
-        if t.control_flow.borrow().remaining_receive_windows() < (WINDOW_SIZE / 2) as u64 {
-            // if t.control_flow.borrow().remaining_receive_windows() == 0 {
-            Self::schedule(&mut t.recv_buffers_coroutine);
+/// Take one of the doorbells counted in `ConnectionShared::notifications`, if there is one.
+fn take_notification(notifications: &Cell<u64>) -> bool {
+    match notifications.get() {
+        0 => false,
+        pending => {
+            notifications.set(pending - 1);
+            true
         }
-        Self::schedule(&mut t.completions_coroutine);
     }
+}
 
-    /// Checks if work corresponding to `qt` is finished returning the data. Otherwise returns
-    /// None.
-    pub fn wait(&mut self, qt: QueueToken) -> Option<CompletedRequest<u8, BUFFER_SIZE>> {
-        trace!("{}", function_name!());
+fn finish_push<const SIZE: usize>(
+    checksum: bool,
+    mut memory: RdmaMemory<u8, SIZE>,
+) -> CompletedRequest<u8, SIZE> {
+    if checksum {
+        // Hand back the buffer as the user filled it.
+        let _ = checksum::strip(&mut memory);
+    }
+    CompletedRequest::Push(memory)
+}
 
-        let task: &mut ConnectionTask<RECV_WRS, SEND_WRS, WINDOW_SIZE, BUFFER_SIZE> =
-            self.tasks.get_mut(qt.task_id.0).unwrap();
-        match qt.op {
-            QueueTokenOp::Push { work_id } => task
-                .completed_pushes
-                .borrow_mut()
-                .remove(&work_id)
-                .map(|p| CompletedRequest::Push(p)),
-            // Just take the next ready one. It doesn't matter for pops.
-            QueueTokenOp::Pop => task
-                .completed_pops
-                .borrow_mut()
-                .pop()
-                .map(|p| CompletedRequest::Pop(p)),
+fn finish_pop<const SIZE: usize>(
+    checksum: bool,
+    task: TaskHandle,
+    mut memory: RdmaMemory<u8, SIZE>,
+) -> CompletedRequest<u8, SIZE> {
+    if !checksum {
+        let bytes = memory.accessed();
+        return CompletedRequest::Pop(memory, bytes);
+    }
+    match checksum::strip(&mut memory) {
+        None => {
+            let bytes = memory.accessed();
+            CompletedRequest::Pop(memory, bytes)
+        }
+        Some((expected, actual)) => {
+            warn!(
+                "Checksum mismatch on {:?}: expected {:#010x}, got {:#010x}.",
+                task, expected, actual
+            );
+            CompletedRequest::Corrupt {
+                memory,
+                expected,
+                actual,
+            }
         }
     }
 }
 
+/// Which headers go in front of pushed payloads, see `IoQueueConfig::checksum` and
+/// `IoQueueConfig::notifications`.
+#[derive(Debug, Copy, Clone)]
+struct Framing {
+    checksum: bool,
+    notifications: bool,
+}
+
 struct WorkRequest<const SIZE: usize> {
     memory: RdmaMemory<u8, SIZE>,
     work_id: u64,
+    /// When `push` was called, if latencies are tracked.
+    submitted: Option<Instant>,
 }
 
-struct SendWindows<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize> {
+/// Work requests posted to the NIC by work id, each with when it was submitted if
+/// `IoQueueConfig::latency_histograms` is set.
+type PostedRequests<const SIZE: usize> =
+    RefCell<WorkSlots<(RdmaMemory<u8, SIZE>, Option<Instant>)>>;
+
+struct SendWindows<
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const WINDOW_SIZE: usize,
+    const SIZE: usize,
+> {
     control_flow: Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>,
+    shared: Rc<ConnectionShared<SIZE>>,
 }
 
-/// Pending until more send windows are allocated by other side.
-impl<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize> Stream
-    for SendWindows<RECV_WRS, SEND_WRS, WINDOW_SIZE>
+/// Pending until more send windows are allocated by other side. Ready with 0 windows if a queued
+/// push was cancelled in the meantime, so its buffer goes back to the pool without waiting.
+impl<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize, const SIZE: usize>
+    Stream for SendWindows<RECV_WRS, SEND_WRS, WINDOW_SIZE, SIZE>
 {
     type Item = u64;
 
@@ -350,6 +1953,9 @@ impl<const RECV_WRS: usize, const SEND_WRS: usize, const WINDOW_SIZE: usize> Str
                     cf.ack_peer_recv_windows();
                     return Poll::Ready(Some(recv_windows));
                 }
+                if !self.shared.cancelled_pushes.borrow().is_empty() {
+                    return Poll::Ready(Some(0));
+                }
                 Poll::Pending
             }
             n => Poll::Ready(Some(n)),
@@ -367,16 +1973,27 @@ async fn push_coroutine<
     mut queue_pairs: QueuePair<RECV_WRS, SEND_WRS>,
     push_work: async_channel::Receiver<WorkRequest<SIZE>>,
     control_flow: Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>,
-    processed_requests: Rc<RefCell<HashMap<u64, RdmaMemory<u8, SIZE>>>>,
+    memory_pool: SharedMemoryPool<SIZE>,
+    shared: Rc<ConnectionShared<SIZE>>,
 ) {
+    let ConnectionShared {
+        processed_requests,
+        cancelled_pushes,
+        rate_limit,
+        unposted,
+        send_batch,
+        ..
+    } = &*shared;
     let s = span!(Level::INFO, "push_coroutine");
     s.in_scope(|| debug!("started!"));
     let mut send_windows = SendWindows {
         control_flow: control_flow.clone(),
+        shared: shared.clone(),
     };
 
     let mut work_requests: VecDeque<WorkRequest<SIZE>> = VecDeque::with_capacity(WINDOW_SIZE);
     let mut requests: VecDeque<(u64, RdmaMemory<u8, SIZE>)> = VecDeque::with_capacity(WINDOW_SIZE);
+    let mut submitted: VecDeque<Option<Instant>> = VecDeque::with_capacity(WINDOW_SIZE);
 
     loop {
         let available_windows = send_windows
@@ -386,36 +2003,124 @@ async fn push_coroutine<
 
         s.in_scope(|| debug!("{} send windows currently available.", available_windows));
 
-        if work_requests.is_empty() {
+        if available_windows > 0 && work_requests.is_empty() {
             // Yield until something comes along.
             work_requests.push_back(push_work.recv().await.unwrap())
         }
-        // Add all other entries now that we know we have at least one.
-        for _ in 0..push_work.len() {
-            work_requests.push_back(push_work.try_recv().expect("TODO"));
+        // Only take as many entries as we have windows for. The rest stay in the bounded
+        // channel so `push` sees backpressure instead of us queueing without limit.
+        while work_requests.len() < available_windows as usize {
+            match push_work.try_recv() {
+                Ok(wr) => work_requests.push_back(wr),
+                Err(_) => break,
+            }
+        }
+
+        // Anything cancelled while it was queued goes straight back to the pool unsent, window or
+        // not. Cancelled pushes still in the channel are dug out of it, the ones queued in front
+        // of them stay here in order.
+        if !cancelled_pushes.borrow().is_empty() {
+            let mut cancelled_pushes = cancelled_pushes.borrow_mut();
+            let mut memory_pool = memory_pool.borrow_mut();
+            let mut unposted = unposted.borrow_mut();
+            let mut recycle = |mut wr: WorkRequest<SIZE>| {
+                s.in_scope(|| debug!("Dropping cancelled push {}.", wr.work_id));
+                wr.memory.reset_access();
+                memory_pool.recycle(wr.memory);
+                unposted.remove(&wr.work_id);
+            };
+            let mut i = 0;
+            while i < work_requests.len() {
+                if cancelled_pushes.remove(&work_requests[i].work_id) {
+                    recycle(work_requests.remove(i).unwrap());
+                } else {
+                    i += 1;
+                }
+            }
+            while !cancelled_pushes.is_empty() {
+                match push_work.try_recv() {
+                    Ok(wr) if cancelled_pushes.remove(&wr.work_id) => recycle(wr),
+                    Ok(wr) => work_requests.push_back(wr),
+                    Err(_) => {
+                        // Nothing is queued anymore, so these can't be waiting to be posted.
+                        cancelled_pushes.clear();
+                    }
+                }
+            }
+        }
+        if available_windows == 0 || work_requests.is_empty() {
+            continue;
         }
 
         // Send as many requests as possible based on the available windows.
         let requests_number = min(work_requests.len(), available_windows as usize);
+        let requests_number = match send_batch.get() {
+            Some(batch) => min(requests_number, batch),
+            None => requests_number,
+        };
+        // `write_copy` posts onto the send queue behind our back, only take what's left of it.
+        let receives = control_flow.borrow().remaining_receive_windows();
+        let requests_number = min(
+            requests_number,
+            SEND_WRS.saturating_sub(shared.in_flight_sends(receives)),
+        );
+        if requests_number == 0 {
+            s.in_scope(|| debug!("Send queue full, waiting for sends to complete."));
+            Yield::new().await;
+            continue;
+        }
+        let requests_number = match rate_limit.borrow_mut().as_mut() {
+            Some(bucket) => bucket.admit(
+                work_requests
+                    .iter()
+                    .take(requests_number)
+                    .map(|wr| wr.memory.accessed()),
+            ),
+            None => requests_number,
+        };
+        if requests_number == 0 {
+            s.in_scope(|| debug!("Rate limited, waiting for the bucket to refill."));
+            Refilled {
+                rate_limit: rate_limit.clone(),
+                bytes: work_requests[0].memory.accessed(),
+            }
+            .await;
+            continue;
+        }
         s.in_scope(|| debug!("Sending {} requests.", requests_number));
 
         for wr in work_requests.drain(..requests_number) {
             requests.push_back((wr.work_id, wr.memory));
+            submitted.push_back(wr.submitted);
         }
 
         queue_pairs.post_send(requests.iter(), PostSendOpcode::Send);
 
-        let mut processed_push_requests = processed_requests.borrow_mut();
-        for (work_id, memory) in requests.drain(..requests_number) {
-            assert!(
-                processed_push_requests.insert(work_id, memory).is_none(),
-                "duplicate entry"
-            );
+        {
+            // Scoped so the borrow is gone before the next await.
+            let mut processed_push_requests = processed_requests.borrow_mut();
+            let mut unposted = unposted.borrow_mut();
+            for ((work_id, memory), submitted) in
+                requests.drain(..requests_number).zip(submitted.drain(..))
+            {
+                unposted.remove(&work_id);
+                assert!(
+                    processed_push_requests
+                        .insert(work_id, (memory, submitted))
+                        .is_none(),
+                    "duplicate entry"
+                );
+            }
         }
         s.in_scope(|| debug!("{} requests sent!", requests_number));
         control_flow
             .borrow_mut()
             .subtract_remaining_send_windows(requests_number as u64);
+
+        if send_batch.get().is_some() {
+            // Let everything else sharing this thread have a go before posting the next batch.
+            Yield::new().await;
+        }
     }
 }
 
@@ -453,17 +2158,27 @@ async fn recv_buffers_coroutine<
     control_flow: Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>,
     // Reference to our Executor's memory poll. We take entries for here for our post_receive RDMA
     // operation.
-    memory_pool: Rc<RefCell<VecDeque<RdmaMemory<u8, SIZE>>>>,
-    processed_requests: Rc<RefCell<HashMap<u64, RdmaMemory<u8, SIZE>>>>,
-    // Actual counter used to keep track of what work_id we are on. This value is shared with
-    // with the push operation that increments it by 1. We increment it by `how_many` based on
-    // the new number of recv windows to allocate. But pop needs to know what numbers we reserved
-    // for recv buffer pops. Thus, the need for both work_id_counter and ready_pop_work_id.
-    work_id_counter: Rc<RefCell<u64>>,
+    memory_pool: SharedMemoryPool<SIZE>,
+    shared: Rc<ConnectionShared<SIZE>>,
     // Our `pop` operation knows what work ID to assign to the next based on the integers we
     // send down this channel.
     // ready_pop_work_id: Sender<u64>,
+    // Posted as soon as we are first polled, during connection setup.
+    initial_posts: u64,
 ) {
+    let ConnectionShared {
+        processed_requests,
+        // Actual counter used to keep track of what work_id we are on. This value is shared with
+        // with the push operation that increments it by 1. We increment it by `how_many` based on
+        // the new number of recv windows to allocate. But pop needs to know what numbers we
+        // reserved for recv buffer pops. Thus, the need for both work_id_counter and
+        // ready_pop_work_id.
+        work_id_counter,
+        stats,
+        pool_exhausted_hook,
+        ..
+    } = &*shared;
+    let track_latency = shared.latency.is_some();
     let mut receive_buffers: Vec<(u64, RdmaMemory<u8, SIZE>)> = Vec::with_capacity(WINDOW_SIZE);
 
     let s = span!(Level::INFO, "post_receive_coroutine");
@@ -472,24 +2187,60 @@ async fn recv_buffers_coroutine<
         control_flow: control_flow.clone(),
     };
 
+    let mut initial_posts = Some(initial_posts).filter(|n| *n > 0);
+    // Only count running dry once until the pool recovers.
+    let mut exhausted = false;
     loop {
-        let how_many = recv_windows
-            .next()
-            .await
-            .expect("Our streams should never end.");
+        let how_many = match initial_posts.take() {
+            Some(n) => n,
+            None => recv_windows
+                .next()
+                .await
+                .expect("Our streams should never end."),
+        };
 
         s.in_scope(|| info!("Allocating {} new receive buffers!", how_many));
 
-        let work_id: u64 = work_id_counter.deref().borrow().clone();
+        let work_id: u64 = *work_id_counter.borrow();
 
         for i in work_id..work_id + how_many {
-            let memory = memory_pool
-                .borrow_mut()
-                .pop_front()
-                .expect("Memory pool is empty.");
-            receive_buffers.push((i, memory));
+            match memory_pool.borrow_mut().allocate() {
+                Some(memory) => receive_buffers.push((i, memory)),
+                None => break,
+            }
+        }
+        let wanted = how_many;
+        let how_many = receive_buffers.len() as u64;
+        if how_many < wanted {
+            if !exhausted {
+                exhausted = true;
+                s.in_scope(|| {
+                    warn!(
+                        "Memory pool is empty, only {} of {} receive buffers posted.",
+                        how_many, wanted
+                    )
+                });
+                let mut current = stats.get();
+                current.pool_exhausted += 1;
+                stats.set(current);
+                if let Some(hook) = pool_exhausted_hook.borrow_mut().as_mut() {
+                    hook(current);
+                }
+            }
+        } else {
+            exhausted = false;
+        }
+        if how_many == 0 {
+            // Try again once something has been freed.
+            Yield::new().await;
+            continue;
         }
 
+        let posted = if track_latency {
+            Some(Instant::now())
+        } else {
+            None
+        };
         queue_pair.post_receive(receive_buffers.iter());
 
         s.in_scope(|| {
@@ -500,15 +2251,26 @@ async fn recv_buffers_coroutine<
             )
         });
 
-        let mut processed_requests = processed_requests.borrow_mut();
-        for (work_id, memory) in receive_buffers.drain(..how_many as usize) {
-            assert!(
-                processed_requests.insert(work_id, memory).is_none(),
-                "duplicate entry"
-            );
+        {
+            // Scoped so the borrow is gone before the next await.
+            let mut processed_requests = processed_requests.borrow_mut();
+            for (work_id, memory) in receive_buffers.drain(..how_many as usize) {
+                assert!(
+                    processed_requests
+                        .insert(work_id, (memory, posted))
+                        .is_none(),
+                    "duplicate entry"
+                );
+            }
         }
         *work_id_counter.borrow_mut() += how_many;
         control_flow.borrow_mut().add_recv_windows(how_many);
+        let mut current = stats.get();
+        current.receives_posted += how_many;
+        stats.set(current);
+        if exhausted {
+            Yield::new().await;
+        }
     }
 }
 
@@ -520,65 +2282,164 @@ async fn completions_coroutine<
     const SIZE: usize,
 >(
     control_flow: Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>,
-    cq: CompletionQueue<CQ_MAX_ELEMENTS>,
-    completed_pushes: Rc<RefCell<HashMap<u64, RdmaMemory<u8, SIZE>>>>,
-    completed_pops: Rc<RefCell<Vec<RdmaMemory<u8, SIZE>>>>,
-    processed_requests: Rc<RefCell<HashMap<u64, RdmaMemory<u8, SIZE>>>>,
+    completion_queue: SharedCompletionQueue<CQ_MAX_ELEMENTS>,
+    memory_pool: SharedMemoryPool<SIZE>,
+    shared: Rc<ConnectionShared<SIZE>>,
+    framing: Framing,
+    task: TaskHandle,
 ) -> () {
+    let ConnectionShared {
+        completed_pushes,
+        copied_pushes,
+        posted_writes,
+        completed_copies,
+        completed_pops,
+        processed_requests,
+        last_activity,
+        connection_error,
+        stats,
+        congestion_hook,
+        peer_send_closed,
+        notifications: pending_notifications,
+        latency,
+        ..
+    } = &*shared;
     let s = span!(Level::INFO, "completions_coroutine");
     s.in_scope(|| info!("started!"));
 
-    let mut event_stream = AsyncCompletionQueue::<CQ_MAX_ELEMENTS> { cq };
     // It might looks like this line doesn't do anything but it does. We need `control_flow`
     // to get dropped before completion queue. As the queue pair inside control flow must
     // be deallocated before the completion queue. This ensures control_flow is dropped
     // before cq... Sorry.
     let control_flow = control_flow;
     loop {
-        let completed = event_stream
-            .next()
+        // Only borrowed while polling: `quiesce` uses it too.
+        let completed = poll_fn(|cx| completion_queue.borrow_mut().poll_next_unpin(cx))
             .await
             .expect("Our stream should never end.");
-        let time = Instant::now();
+        let now = Instant::now();
+        last_activity.set(now);
         s.in_scope(|| info!("{} events completed!.", completed.len()));
 
         let mut recv_requests_completed = 0;
-        let mut completed_pops = completed_pops.borrow_mut();
-        let mut completed_pushes = completed_pushes.borrow_mut();
-        let mut processed_requests = processed_requests.borrow_mut();
-
-        for c in completed {
-            s.in_scope(|| trace!("Work completion status for {}: {}", c.wr_id, c.status));
-            if c.status != rdma_cm::ffi::ibv_wc_status_IBV_WC_SUCCESS {
-                panic!("Completion queue event with error status {}.", c.status);
-            }
+        // Scoped, otherwise we would be awaiting while still holding RefCells.
+        {
+            let mut popped = completed_pops.borrow_mut();
+            let mut completed_pushes = completed_pushes.borrow_mut();
+            let mut processed_requests = processed_requests.borrow_mut();
+
+            for c in completed {
+                s.in_scope(|| trace!("Work completion status for {}: {}", c.wr_id, c.status));
+                if c.status != rdma_cm::ffi::ibv_wc_status_IBV_WC_SUCCESS {
+                    if record_congestion(stats, c.status) {
+                        if let Some((threshold, hook)) = congestion_hook.borrow_mut().as_mut() {
+                            let current = stats.get();
+                            if current.congestion_events() == *threshold {
+                                hook(current);
+                            }
+                        }
+                    }
+                    // The QP is now in the error state and will flush everything else with an error
+                    // too, so only the first failure is interesting.
+                    if connection_error.get().is_none() {
+                        s.in_scope(|| {
+                            error!("Completion queue event with error status {}.", c.status)
+                        });
+                        connection_error.set(Some(IoQueueError::WorkCompletion(c.status)));
+                    }
+                    // Either this request failed or it was flushed because an earlier one did. The
+                    // NIC is done with its buffer either way, so put it back in the pool instead of
+                    // stranding the connection's whole working set. Its token resolves to
+                    // `CompletedRequest::Error` through `connection_error`.
+                    if let Some((mut memory, _)) = processed_requests.remove(&c.wr_id) {
+                        memory.reset_access();
+                        memory_pool.borrow_mut().recycle(memory);
+                    }
+                    continue;
+                }
 
-            if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RECV {
-                let mut memory = processed_requests.remove(&c.wr_id).
+                count_completion(stats, c.opcode);
+                if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RECV {
+                    let (mut memory, posted) = processed_requests.remove(&c.wr_id).
                     // This should be impossible.
                     expect("Processed entry for completed wr missing.");
 
-                recv_requests_completed += 1;
-                let bytes_transferred = c.byte_len as usize;
-                memory.initialize_length(bytes_transferred);
-                completed_pops.push(memory);
-                let elapsed = time.elapsed();
-                TIME.with(|time| *time.borrow_mut() += elapsed.as_nanos() as u32);
-            } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_SEND {
-                let memory = processed_requests.remove(&c.wr_id).
+                    recv_requests_completed += 1;
+                    let bytes_transferred = c.byte_len as usize;
+                    if bytes_transferred == 0 {
+                        // The peer's end-of-stream marker. Pops see it once `completed_pops` is
+                        // empty.
+                        s.in_scope(|| info!("Peer shut down its send direction."));
+                        peer_send_closed.set(true);
+                        memory.reset_access();
+                        memory_pool.borrow_mut().recycle(memory);
+                        continue;
+                    }
+                    if let (Some(latency), Some(posted)) = (&latency, posted) {
+                        latency
+                            .borrow_mut()
+                            .pop
+                            .record(now.saturating_duration_since(posted));
+                    }
+                    memory.initialize_length(bytes_transferred);
+                    if framing.notifications && notify::strip(&mut memory) == Some(notify::NOTIFY) {
+                        pending_notifications.set(pending_notifications.get() + 1);
+                        memory.reset_access();
+                        memory_pool.borrow_mut().recycle(memory);
+                        continue;
+                    }
+                    popped.push(memory);
+                } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_SEND {
+                    let (mut memory, submitted) = processed_requests.remove(&c.wr_id).
                     // This should be impossible.
                     expect("Processed entry for completed wr missing.");
 
-                assert!(
-                    completed_pushes.insert(c.wr_id, memory).is_none(),
-                    "duplicate entry"
-                );
-            } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RDMA_WRITE {
-                debug!("RDMA Write succeeded.");
-            } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RDMA_READ {
-                debug!("RDMA Read succeeded.");
-            } else {
-                panic!("Unknown ibv_wc opcode: {:?}", c.opcode);
+                    if let (Some(latency), Some(submitted)) = (&latency, submitted) {
+                        latency
+                            .borrow_mut()
+                            .push
+                            .record(now.saturating_duration_since(submitted));
+                    }
+                    if let Some(tracked) = copied_pushes.borrow_mut().remove(&c.wr_id) {
+                        memory.reset_access();
+                        memory_pool.borrow_mut().recycle(memory);
+                        if tracked {
+                            completed_copies.borrow_mut().insert(c.wr_id);
+                        }
+                        continue;
+                    }
+                    if framing.notifications {
+                        // Hand back the buffer as the user filled it.
+                        let _ = notify::strip(&mut memory);
+                    }
+                    assert!(
+                        completed_pushes.insert(c.wr_id, memory).is_none(),
+                        "duplicate entry"
+                    );
+                } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RDMA_WRITE {
+                    if let Some(tracked) = posted_writes.borrow_mut().remove(&c.wr_id) {
+                        let (mut memory, _) = processed_requests.remove(&c.wr_id).
+                        // This should be impossible.
+                        expect("Processed entry for completed wr missing.");
+                        memory.reset_access();
+                        memory_pool.borrow_mut().recycle(memory);
+                        if tracked {
+                            completed_copies.borrow_mut().insert(c.wr_id);
+                        }
+                    } else if c.wr_id == KEEPALIVE_WORK_ID {
+                        debug!("Keepalive probe acknowledged.");
+                    } else {
+                        debug!("RDMA Write succeeded.");
+                    }
+                } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RDMA_READ {
+                    debug!("RDMA Read succeeded.");
+                } else if c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_COMP_SWAP
+                    || c.opcode == rdma_cm::ffi::ibv_wc_opcode_IBV_WC_FETCH_ADD
+                {
+                    debug!("Atomic succeeded.");
+                } else {
+                    panic!("Unknown ibv_wc opcode: {:?}", c.opcode);
+                }
             }
         }
 
@@ -586,14 +2447,133 @@ async fn completions_coroutine<
             .borrow_mut()
             .subtract_recv_windows(recv_requests_completed);
 
-        // Needed otherwise we would be awaiting while still holding RefCells.
-        drop(completed_pops);
-        drop(completed_pushes);
-        drop(processed_requests);
+        dispatch_pop_callbacks(&shared, &memory_pool, framing.checksum, task);
         Yield::new().await;
     }
 }
 
+/// Hand completed pops to the callbacks registered with `pop_with_callback`, with the same
+/// precedence as `wait`. Nothing is borrowed while a callback runs.
+fn dispatch_pop_callbacks<const SIZE: usize>(
+    shared: &ConnectionShared<SIZE>,
+    memory_pool: &SharedMemoryPool<SIZE>,
+    checksum: bool,
+    task: TaskHandle,
+) {
+    let ConnectionShared {
+        pop_callbacks: callbacks,
+        completed_pops,
+        peer_send_closed,
+        notifications,
+        connection_error,
+        ..
+    } = shared;
+    while !callbacks.borrow().is_empty() {
+        let popped = completed_pops.borrow_mut().pop();
+        let completed = match popped {
+            Some(memory) => finish_pop(checksum, task, memory),
+            None if take_notification(notifications) => CompletedRequest::Notify,
+            None if peer_send_closed.get() => CompletedRequest::EndOfStream,
+            None => match connection_error.get() {
+                Some(e) => CompletedRequest::Error(e),
+                None => return,
+            },
+        };
+        let callback = callbacks.borrow_mut().pop_front().unwrap();
+        if let Some(mut memory) = callback(completed) {
+            memory.reset_access();
+            memory_pool.borrow_mut().recycle(memory);
+        }
+    }
+}
+
+/// Pending until nothing has completed on this connection for `interval`.
+struct IdleFor<'a> {
+    interval: Duration,
+    last_activity: &'a Cell<Instant>,
+}
+
+impl Future for IdleFor<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.last_activity.get().elapsed() >= self.interval {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Resolves to `true` once something completes after `sent`, or `false` if `timeout` elapses
+/// first.
+struct ProbeAnswered<'a> {
+    sent: Instant,
+    timeout: Duration,
+    last_activity: &'a Cell<Instant>,
+}
+
+impl Future for ProbeAnswered<'_> {
+    type Output = bool;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if self.last_activity.get() > self.sent {
+            Poll::Ready(true)
+        } else if self.sent.elapsed() >= self.timeout {
+            Poll::Ready(false)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Probes idle connections so a silently dead peer is noticed instead of leaving `wait` hanging
+/// forever. Any completion counts as proof of life, so busy connections never send probes.
+async fn keepalive_coroutine<
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const WINDOW_SIZE: usize,
+    const SIZE: usize,
+>(
+    control_flow: Rc<RefCell<ControlFlow<RECV_WRS, SEND_WRS, WINDOW_SIZE>>>,
+    interval: Duration,
+    timeout: Duration,
+    shared: Rc<ConnectionShared<SIZE>>,
+) {
+    let ConnectionShared {
+        last_activity,
+        connection_error,
+        ..
+    } = &*shared;
+    let s = span!(Level::INFO, "keepalive_coroutine");
+    s.in_scope(|| debug!("started!"));
+
+    while connection_error.get().is_none() {
+        IdleFor {
+            interval,
+            last_activity,
+        }
+        .await;
+
+        s.in_scope(|| debug!("Connection idle for {:?}, probing peer.", interval));
+        control_flow.borrow_mut().send_keepalive();
+
+        let answered = ProbeAnswered {
+            sent: Instant::now(),
+            timeout,
+            last_activity,
+        }
+        .await;
+        if !answered && connection_error.get().is_none() {
+            s.in_scope(|| error!("Keepalive probe unanswered after {:?}.", timeout));
+            connection_error.set(Some(IoQueueError::PeerUnresponsive));
+        }
+    }
+
+    // Our coroutines should never finish. There is nothing left to probe.
+    std::future::pending::<()>().await;
+}
+
 /// Yields the first time it is called.
 struct Yield {
     yield_first_time: bool,
@@ -620,17 +2600,110 @@ impl Future for Yield {
     }
 }
 
+/// Count `status` in `stats` if it points at congestion. Returns whether it did.
+fn record_congestion(stats: &Cell<ConnectionStats>, status: rdma_cm::ffi::ibv_wc_status) -> bool {
+    let mut current = stats.get();
+    if status == rdma_cm::ffi::ibv_wc_status_IBV_WC_RNR_RETRY_EXC_ERR {
+        current.rnr_retry_errors += 1;
+    } else if status == rdma_cm::ffi::ibv_wc_status_IBV_WC_RETRY_EXC_ERR {
+        current.retry_errors += 1;
+    } else {
+        return false;
+    }
+    stats.set(current);
+    true
+}
+
+fn count_completion(stats: &Cell<ConnectionStats>, opcode: rdma_cm::ffi::ibv_wc_opcode) {
+    let mut current = stats.get();
+    match opcode {
+        rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RECV => current.recv_completions += 1,
+        rdma_cm::ffi::ibv_wc_opcode_IBV_WC_SEND => current.send_completions += 1,
+        rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RDMA_WRITE => current.rdma_write_completions += 1,
+        rdma_cm::ffi::ibv_wc_opcode_IBV_WC_RDMA_READ => current.rdma_read_completions += 1,
+        rdma_cm::ffi::ibv_wc_opcode_IBV_WC_COMP_SWAP
+        | rdma_cm::ffi::ibv_wc_opcode_IBV_WC_FETCH_ADD => current.atomic_completions += 1,
+        _ => return,
+    }
+    stats.set(current);
+}
+
+const WRONG_INSTANCE_HINT: &str = "Token or queue descriptor belongs to a different IoQueue. \
+                                   Each IoQueue only knows its own connections.";
+
+const FOREIGN_BUFFER_HINT: &str = "Buffer was not allocated by this connection's malloc. You \
+                                   likely mixed up connection descriptors.";
+
+/// Wraps a coroutine so it finishes as soon as `shutdown` is set. The coroutine itself isn't
+/// dropped at that point; it stays inside its `ConnectionTask` until the task is dropped, which
+/// keeps the drop order the task relies on.
+struct UntilShutdown {
+    shutdown: Rc<Cell<bool>>,
+    coroutine: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Future for UntilShutdown {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        if this.shutdown.get() {
+            return Poll::Ready(());
+        }
+        this.coroutine.as_mut().poll(cx)
+    }
+}
+
+fn until_shutdown(
+    shutdown: &Rc<Cell<bool>>,
+    coroutine: impl Future<Output = ()> + 'static,
+) -> Pin<Box<dyn Future<Output = ()>>> {
+    Box::pin(UntilShutdown {
+        shutdown: shutdown.clone(),
+        coroutine: Box::pin(coroutine),
+    })
+}
+
+/// Yields at most `batch_size` completions per poll. Whatever the CQ returned beyond that is kept
+/// in `pending` and handed out before the CQ is polled again.
 struct AsyncCompletionQueue<const CQ_MAX_ELEMENTS: usize> {
     cq: rdma_cm::CompletionQueue<CQ_MAX_ELEMENTS>,
+    batch_size: usize,
+    pending: Option<arrayvec::IntoIter<rdma_cm::ffi::ibv_wc, CQ_MAX_ELEMENTS>>,
+}
+
+type SharedCompletionQueue<const CQ_MAX_ELEMENTS: usize> =
+    Rc<RefCell<AsyncCompletionQueue<CQ_MAX_ELEMENTS>>>;
+
+impl<const CQ_MAX_ELEMENTS: usize> AsyncCompletionQueue<CQ_MAX_ELEMENTS> {
+    /// The next batch, or `None` if nothing has completed.
+    fn poll_batch(&mut self) -> Option<arrayvec::ArrayVec<rdma_cm::ffi::ibv_wc, CQ_MAX_ELEMENTS>> {
+        let mut entries = match self.pending.take() {
+            Some(entries) if entries.len() > 0 => entries,
+            _ => self.cq.poll()?,
+        };
+
+        let mut batch = arrayvec::ArrayVec::new();
+        for _ in 0..self.batch_size {
+            match entries.next() {
+                Some(entry) => batch.push(entry),
+                None => break,
+            }
+        }
+        if entries.len() > 0 {
+            self.pending = Some(entries);
+        }
+        Some(batch)
+    }
 }
 
 impl<const CQ_MAX_ELEMENTS: usize> Stream for AsyncCompletionQueue<CQ_MAX_ELEMENTS> {
-    type Item = arrayvec::IntoIter<rdma_cm::ffi::ibv_wc, CQ_MAX_ELEMENTS>;
+    type Item = arrayvec::ArrayVec<rdma_cm::ffi::ibv_wc, CQ_MAX_ELEMENTS>;
 
-    fn poll_next(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match self.cq.poll() {
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.poll_batch() {
             None => Poll::Pending,
-            Some(entries) => Poll::Ready(Some(entries)),
+            batch => Poll::Ready(batch),
         }
     }
 }
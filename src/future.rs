@@ -0,0 +1,252 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::error::IoQueueError;
+use crate::executor::{CompletedRequest, DisconnectReason, QueueToken, TaskHandle};
+use crate::IoQueue;
+#[allow(unused_imports)]
+use tracing::{debug, info, trace, warn, Level};
+
+/// Future returned by `IoQueue::push_async` and `IoQueue::pop_async`, resolving to the
+/// request's completion.
+///
+/// There is no reactor driving the NIC, so every poll runs one step of the executor and, if
+/// the request isn't done yet, wakes itself up again right away. This busy-polls just like
+/// `wait` does, but lets the caller `.await` from inside an `async fn`.
+///
+/// Dropping the future before it resolves cancels the request. Pushes already posted to the NIC
+/// can't be cancelled, so dropping one of those blocks until the NIC is done with the memory and
+/// then returns it to the pool.
+pub struct Completion<
+    'a,
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const CQ_ELEMENTS: usize,
+    const WINDOW_SIZE: usize,
+    const BUFFER_SIZE: usize,
+> {
+    io_queue: &'a mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+    qt: QueueToken,
+    start: Instant,
+    done: bool,
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Completion<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    pub(crate) fn new(
+        io_queue: &'a mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        qt: QueueToken,
+    ) -> Self {
+        Completion {
+            io_queue,
+            qt,
+            start: Instant::now(),
+            done: false,
+        }
+    }
+
+    pub fn queue_token(&self) -> QueueToken {
+        self.qt
+    }
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Future for Completion<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    type Output = CompletedRequest<u8, BUFFER_SIZE>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        assert!(!self.done, "Completion polled after it resolved.");
+        let qt = self.qt;
+        match self.io_queue.try_wait(qt) {
+            Some(cr) => {
+                let elapsed = self.start.elapsed();
+                self.io_queue.executor.record_wait(qt, elapsed);
+                self.done = true;
+                Poll::Ready(cr)
+            }
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Future returned by `IoQueue::wait_any_async`, resolving to the index of the first token in
+/// the slice that completed along with its completion. Polls like `Completion` does.
+///
+/// Unlike `Completion`, dropping it leaves every token outstanding; they can still be waited on.
+pub struct WaitAny<
+    'a,
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const CQ_ELEMENTS: usize,
+    const WINDOW_SIZE: usize,
+    const BUFFER_SIZE: usize,
+> {
+    io_queue: &'a mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+    qts: Vec<QueueToken>,
+    start: Instant,
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > WaitAny<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    pub(crate) fn new(
+        io_queue: &'a mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        qts: &[QueueToken],
+    ) -> Self {
+        WaitAny {
+            io_queue,
+            qts: qts.to_vec(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Future for WaitAny<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    type Output = (usize, CompletedRequest<u8, BUFFER_SIZE>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        match this.io_queue.try_wait_any(&this.qts) {
+            Some((i, cr)) => {
+                let elapsed = this.start.elapsed();
+                this.io_queue.executor.record_wait(this.qts[i], elapsed);
+                Poll::Ready((i, cr))
+            }
+            None => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Future returned by `IoQueue::closed`. Polls like `Completion` does.
+///
+/// A peer that vanishes without a word is only noticed once something on the connection fails,
+/// its posted receives or the keepalive probe if enabled, since CM events can't be read without
+/// blocking.
+pub struct Closed<
+    'a,
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const CQ_ELEMENTS: usize,
+    const WINDOW_SIZE: usize,
+    const BUFFER_SIZE: usize,
+> {
+    io_queue: &'a mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+    task: Option<TaskHandle>,
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Closed<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    pub(crate) fn new(
+        io_queue: &'a mut IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        task: Option<TaskHandle>,
+    ) -> Self {
+        Closed { io_queue, task }
+    }
+
+    fn reason(&self) -> Option<DisconnectReason> {
+        match self.task {
+            Some(task) => self.io_queue.executor.disconnect_reason(task),
+            // Never connected.
+            None => Some(DisconnectReason::Failed(IoQueueError::ConnectionClosed)),
+        }
+    }
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Future for Closed<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    type Output = DisconnectReason;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        for _ in 0..2 {
+            if let Some(reason) = self.reason() {
+                return Poll::Ready(reason);
+            }
+            self.io_queue.executor.poll_all_tasks();
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+impl<
+        'a,
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    > Drop for Completion<'a, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
+{
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if let Err(e) = self.io_queue.cancel(self.qt) {
+            debug!(
+                "Dropped completion could not be cancelled: {}. Waiting for it.",
+                e
+            );
+            // Once the connection dies every token resolves, so this can't spin forever.
+            let completed = loop {
+                if let Some(cr) = self.io_queue.try_wait(self.qt) {
+                    break cr;
+                }
+            };
+            if let CompletedRequest::Push(memory) = completed {
+                if let Err(e) = self.io_queue.executor.free(self.qt.task_id, memory) {
+                    warn!("Unable to free dropped push: {}.", e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! Per-connection latency distributions, see `IoQueueConfig::latency_histograms`.
+use std::time::Duration;
+
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+/// Enough buckets for any `u64` nanosecond count.
+const BUCKETS: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKETS;
+
+/// Latency percentiles of one kind of operation on a connection. Every value is accurate to
+/// within about 3%.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Percentiles {
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    /// Operations recorded. All percentiles are zero when this is.
+    pub samples: u64,
+}
+
+/// Log-linear histogram in the style of HDR histograms: every power of two is split into
+/// `SUB_BUCKETS` equal buckets, so the error relative to the recorded value is bounded no
+/// matter how large it is, and recording is an index computation and an increment.
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    samples: u64,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            counts: vec![0; BUCKETS],
+            samples: 0,
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let nanos = latency.as_nanos().min(u64::MAX as u128) as u64;
+        self.counts[bucket(nanos)] += 1;
+        self.samples += 1;
+    }
+
+    pub fn reset(&mut self) {
+        for count in self.counts.iter_mut() {
+            *count = 0;
+        }
+        self.samples = 0;
+    }
+
+    pub fn percentiles(&self) -> Percentiles {
+        Percentiles {
+            p50: self.percentile(0.5),
+            p90: self.percentile(0.9),
+            p99: self.percentile(0.99),
+            p999: self.percentile(0.999),
+            samples: self.samples,
+        }
+    }
+
+    fn percentile(&self, quantile: f64) -> Duration {
+        if self.samples == 0 {
+            return Duration::default();
+        }
+        let rank = ((quantile * self.samples as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_nanos(highest_in_bucket(bucket));
+            }
+        }
+        unreachable!(
+            "Bucket counts add up to fewer than {} samples.",
+            self.samples
+        );
+    }
+}
+
+/// The push and pop distributions of one connection.
+pub struct ConnectionLatency {
+    pub push: LatencyHistogram,
+    pub pop: LatencyHistogram,
+}
+
+impl ConnectionLatency {
+    pub fn new() -> ConnectionLatency {
+        ConnectionLatency {
+            push: LatencyHistogram::new(),
+            pop: LatencyHistogram::new(),
+        }
+    }
+}
+
+fn bucket(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    // Values in [2^magnitude, 2^(magnitude + 1)) share a bucket per 2^shift.
+    let magnitude = 63 - value.leading_zeros();
+    let shift = magnitude - SUB_BUCKET_BITS;
+    let sub_bucket = (value >> shift) as usize - SUB_BUCKETS;
+    (shift as usize + 1) * SUB_BUCKETS + sub_bucket
+}
+
+fn highest_in_bucket(bucket: usize) -> u64 {
+    if bucket < SUB_BUCKETS {
+        return bucket as u64;
+    }
+    let shift = (bucket / SUB_BUCKETS - 1) as u32;
+    let lowest = ((bucket % SUB_BUCKETS + SUB_BUCKETS) as u64) << shift;
+    lowest + ((1u64 << shift) - 1)
+}
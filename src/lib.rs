@@ -1,27 +1,171 @@
 use std::ptr::null_mut;
 
+use bytemuck::Pod;
 use nix::sys::socket::SockAddr;
 use rdma_cm;
 use rdma_cm::{
-    CommunicationManager, PeerConnectionData, RdmaCmEvent, RdmaMemory, VolatileRdmaMemory,
+    CommunicationManager, CompletionQueue, PeerConnectionData, ProtectionDomain, QueuePair,
+    RdmaCmEvent, RdmaMemory, VolatileRdmaMemory,
 };
 
-use crate::executor::{Executor, QueueTokenOp, TaskHandle, TIME};
+use crate::executor::{Executor, QueueTokenOp};
+use crate::memory_pool::ConnectionResources;
+use crate::message::{fragment_count, FragmentHeader, MessageState, HEADER_SIZE};
+use crate::rpc::RpcState;
+pub use buffer::BufferExt;
+pub use config::{ConnectionSettings, IoQueueConfig};
+pub use connection_pool::{ConnectionPool, PooledConnection};
 use control_flow::ControlFlow;
-pub use executor::{CompletedRequest, QueueToken};
+pub use error::{FreeError, IoQueueError, PushError, RequestError};
+pub use executor::{
+    leaked_teardowns, CompletedRequest, CongestionHook, ConnectionRef, ConnectionStats,
+    ConnectionTiming, DisconnectReason, PoolExhaustedHook, QueueToken, RequestKind, TaskHandle,
+    TokenStatus,
+};
+pub use future::{Closed, Completion, WaitAny};
+pub use latency::Percentiles;
+pub use memory_pool::{MemoryPool, MemoryPoolFactory, RegisteredPool};
+pub use remote_region::RemoteRegion;
+pub use ring::{RingReceiver, RingSender};
+pub use rpc::CallToken;
 
+mod buffer;
+mod checksum;
+mod config;
+mod connection_pool;
 mod control_flow;
+mod error;
 mod executor;
+mod future;
+mod latency;
+mod memory_pool;
+mod message;
+mod notify;
+mod rate_limit;
+mod remote_region;
+mod ring;
+mod rpc;
 mod utils;
 mod waker;
-use std::borrow::BorrowMut;
+mod work_slots;
+use std::collections::VecDeque;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 #[allow(unused_imports)]
-use tracing::{debug, info, trace, Level};
+use tracing::{debug, info, trace, warn, Level};
 
 pub struct QueueDescriptor {
-    cm: rdma_cm::CommunicationManager,
+    /// Shared with the executor once connected, so `IoQueue::shutdown` can disconnect it.
+    cm: Rc<rdma_cm::CommunicationManager>,
     // TODO a better API could avoid having these as options
     scheduler_handle: Option<TaskHandle>,
+    messages: MessageState,
+    rpc: RpcState,
+    /// See `IoQueue::register_remote`.
+    remote_regions: Vec<RemoteRegion>,
+    /// Only set on listeners with `IoQueueConfig::shared_accept_pool`, once the first
+    /// connection has been accepted.
+    accept_resources: Option<usize>,
+}
+
+/// Both ends of a connection made by `IoQueue::connected_pair`.
+pub struct LoopbackPair {
+    pub client: QueueDescriptor,
+    pub server: QueueDescriptor,
+    /// The server end's connection events arrive on the listener's event channel, so it has to
+    /// live as long as the server end does.
+    _listener: QueueDescriptor,
+}
+
+/// A connection whose handshake has been started but not finished, see `connected_pair`.
+struct HalfOpenConnection<
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const CQ_ELEMENTS: usize,
+    const BUFFER_SIZE: usize,
+> {
+    resources: ConnectionResources<BUFFER_SIZE>,
+    cq: CompletionQueue<CQ_ELEMENTS>,
+    qp: QueuePair<RECV_WRS, SEND_WRS>,
+    recv_window: VolatileRdmaMemory<u64, 1>,
+    /// Only set when connecting, see `ConnectionRef::peer_address`.
+    peer_address: Option<String>,
+}
+
+struct HalfOpenAccept<
+    const RECV_WRS: usize,
+    const SEND_WRS: usize,
+    const CQ_ELEMENTS: usize,
+    const BUFFER_SIZE: usize,
+> {
+    connection: HalfOpenConnection<RECV_WRS, SEND_WRS, CQ_ELEMENTS, BUFFER_SIZE>,
+    client_private_data: PeerConnectionData<u64, 1>,
+}
+
+/// How `wait` notices a push that makes no progress because the peer grants no send windows:
+/// nothing on the connection completes for `IoQueueConfig::stall_warning_iterations` checks in a
+/// row while we are out of send windows.
+struct StallCheck {
+    limit: Option<usize>,
+    activity: Instant,
+    idle_iterations: usize,
+    warned: bool,
+}
+
+impl StallCheck {
+    fn new<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    >(
+        executor: &Executor<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        task: TaskHandle,
+    ) -> StallCheck {
+        StallCheck {
+            limit: executor.config().stall_warning_iterations,
+            activity: executor.last_activity(task),
+            idle_iterations: 0,
+            warned: false,
+        }
+    }
+
+    /// Call once per pass spent waiting on `task`. Returns true, after warning about it, the
+    /// first time the connection counts as stalled.
+    fn stalled<
+        const RECV_WRS: usize,
+        const SEND_WRS: usize,
+        const CQ_ELEMENTS: usize,
+        const WINDOW_SIZE: usize,
+        const BUFFER_SIZE: usize,
+    >(
+        &mut self,
+        executor: &Executor<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        task: TaskHandle,
+    ) -> bool {
+        let limit = match self.limit {
+            Some(limit) if !self.warned => limit,
+            _ => return false,
+        };
+        let current = executor.last_activity(task);
+        if current != self.activity {
+            self.activity = current;
+            self.idle_iterations = 0;
+            return false;
+        }
+        self.idle_iterations += 1;
+        if self.idle_iterations >= limit && executor.send_windows_exhausted(task) {
+            warn!(
+                "Push has made no progress for {} iterations: peer has advertised no send \
+                 windows. Is the other side popping?",
+                self.idle_iterations
+            );
+            self.warned = true;
+            return true;
+        }
+        false
+    }
 }
 
 pub struct IoQueue<
@@ -43,11 +187,29 @@ impl<
     > IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>
 {
     pub fn new() -> IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
-        info!("{}", function_name!());
-        IoQueue {
-            executor: Executor::new(),
-        }
+        IoQueue::with_config(IoQueueConfig::default()).expect("Default config is valid.")
+    }
+
+    /// Fails with `InvalidConfig` if `config` can't be used.
+    pub fn with_config(
+        config: IoQueueConfig,
+    ) -> Result<IoQueue<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>, IoQueueError>
+    {
+        info!("{}: {:?}", function_name!(), config);
+        config.validate(WINDOW_SIZE, RECV_WRS)?;
+        Ok(IoQueue {
+            executor: Executor::new(config),
+        })
     }
+    /// Use `factory` to build the memory pool of every connection established from now on,
+    /// instead of the default `RegisteredPool`.
+    pub fn set_memory_pool(
+        &mut self,
+        factory: impl Fn(&mut ProtectionDomain, usize) -> Box<dyn MemoryPool<BUFFER_SIZE>> + 'static,
+    ) {
+        self.executor.set_memory_pool_factory(Box::new(factory));
+    }
+
     /// Initializes RDMA by fetching the device?
     /// Allocates memory regions?
     pub fn socket(&self) -> QueueDescriptor {
@@ -56,8 +218,12 @@ impl<
         let cm = rdma_cm::CommunicationManager::new().expect("TODO");
 
         QueueDescriptor {
-            cm,
+            cm: Rc::new(cm),
             scheduler_handle: None,
+            messages: MessageState::default(),
+            rpc: RpcState::default(),
+            remote_regions: Vec::new(),
+            accept_resources: None,
         }
     }
 
@@ -72,13 +238,135 @@ impl<
     /// 2) resolves route.
     /// 3) Creates protection domain, completion queue, and queue pairs.
     /// 4) Establishes receive window communication.
-    pub fn connect(&mut self, qd: &mut QueueDescriptor, node: &str, service: &str) {
+    ///
+    /// Fails with `AddressUnresolved` if the address still can't be resolved after the retries
+    /// configured in `IoQueueConfig`, and with `ConnectionRefused` if the server turns us away.
+    pub fn connect(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        node: &str,
+        service: &str,
+    ) -> Result<(), IoQueueError> {
+        self.connect_with(qd, node, service, &ConnectionSettings::default())
+    }
+
+    /// Like `connect` but applies `settings` to the new connection.
+    pub fn connect_with(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        node: &str,
+        service: &str,
+        settings: &ConnectionSettings,
+    ) -> Result<(), IoQueueError> {
         info!("{}", function_name!());
 
-        IoQueue::<RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>::resolve_address(
-            qd, node, service,
-        );
+        let peer_address = format!("{}:{}", node, service);
+        self.with_resolution_retries(&peer_address, || Self::resolve_address(qd, node, service))?;
+        self.establish(qd, peer_address, settings)
+    }
+
+    /// Like `connect` but for an address that is already known, skipping `get_address_info`
+    /// and the lookup that comes with it.
+    pub fn connect_addr(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        address: &SockAddr,
+    ) -> Result<(), IoQueueError> {
+        info!("{}: {}", function_name!(), address);
+
+        let peer_address = address.to_string();
+        self.with_resolution_retries(&peer_address, || Self::resolve_sockaddr(qd, address))?;
+        self.establish(qd, peer_address, &ConnectionSettings::default())
+    }
+
+    /// Connect to ourselves through `address`, which must belong to a local RDMA device (e.g.
+    /// a soft-RoCE interface). Both handshakes run on this thread, interleaved so neither side
+    /// blocks waiting for the other. Meant for tests that want to push on one end and pop on
+    /// the other without a second process. Fails with `BindFailed` if `address` can't be listened
+    /// on.
+    pub fn connected_pair(&mut self, address: &SockAddr) -> Result<LoopbackPair, IoQueueError> {
+        info!("{}: {}", function_name!(), address);
+
+        let mut listener = self.socket();
+        listener
+            .cm
+            .bind(address)
+            .map_err(|_| IoQueueError::BindFailed)?;
+        listener.cm.listen().map_err(|_| IoQueueError::BindFailed)?;
 
+        let mut client = self.socket();
+        self.with_resolution_retries(&address.to_string(), || {
+            Self::resolve_sockaddr(&mut client, address)
+        })?;
+        let client_half = self.start_connect(&mut client, address.to_string());
+        let (connected_id, server_half) = self.start_accept(&mut listener);
+        // The client has to process the server's reply (sending the final handshake message)
+        // before the server sees the connection established.
+        let settings = ConnectionSettings::default();
+        self.finish_connect(&mut client, client_half, &settings)?;
+        let server = self.finish_accept(&mut listener, connected_id, server_half, &settings);
+
+        Ok(LoopbackPair {
+            client,
+            server,
+            _listener: listener,
+        })
+    }
+
+    fn resolve_sockaddr(qd: &mut QueueDescriptor, address: &SockAddr) -> bool {
+        let (sockaddr, _) = address.as_ffi_pair();
+        qd.cm
+            .resolve_address(sockaddr as *const _ as *mut _)
+            .is_ok()
+            && Self::address_resolved(qd)
+    }
+
+    /// Run `resolve` until it succeeds, waiting in between attempts. Freshly brought up links
+    /// often fail ARP or route lookups for a moment. The wait keeps polling the connections we
+    /// already have rather than sleeping, so they don't stall while we retry.
+    fn with_resolution_retries(
+        &mut self,
+        address: &str,
+        mut resolve: impl FnMut() -> bool,
+    ) -> Result<(), IoQueueError> {
+        let retries = self.executor.config().address_resolution_retries;
+        let delay = self.executor.config().address_resolution_retry_delay;
+        for attempt in 0..=retries {
+            if attempt > 0 {
+                warn!(
+                    "Unable to resolve {}. Retrying in {:?} ({}/{}).",
+                    address, delay, attempt, retries
+                );
+                let start = Instant::now();
+                while start.elapsed() < delay {
+                    self.executor.poll_all_tasks();
+                }
+            }
+            if resolve() {
+                return Ok(());
+            }
+        }
+        Err(IoQueueError::AddressUnresolved)
+    }
+
+    /// Everything in `connect` after the address has been resolved.
+    fn establish(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        peer_address: String,
+        settings: &ConnectionSettings,
+    ) -> Result<(), IoQueueError> {
+        let half_open = self.start_connect(qd, peer_address);
+        self.finish_connect(qd, half_open, settings)
+    }
+
+    /// Resolve the route, set up the connection's verbs objects and send the connection
+    /// request.
+    fn start_connect(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        peer_address: String,
+    ) -> HalfOpenConnection<RECV_WRS, SEND_WRS, CQ_ELEMENTS, BUFFER_SIZE> {
         // Resolve route
         qd.cm.resolve_route(1).expect("TODO");
         let event = qd.cm.get_cm_event().expect("TODO");
@@ -86,61 +374,105 @@ impl<
         event.ack();
 
         // Allocate pd, cq, and qp.
-        let mut pd = qd.cm.allocate_protection_domain().expect("TODO");
+        let pd = qd.cm.allocate_protection_domain().expect("TODO");
+        let resources = self.executor.new_resources(pd, 1);
         let cq = qd.cm.create_cq().expect("TODO");
-        let qp = qd.cm.create_qp(&pd, &cq);
+        let qp = qd.cm.create_qp(&resources.protection_domain(), &cq);
 
-        let mut our_recv_window = VolatileRdmaMemory::<u64, 1>::new(&mut pd);
+        let mut our_recv_window =
+            VolatileRdmaMemory::<u64, 1>::new(&mut resources.protection_domain());
         qd.cm
             .connect_with_data(&our_recv_window.as_connection_data())
             .expect("TODO");
+        HalfOpenConnection {
+            resources,
+            cq,
+            qp,
+            recv_window: our_recv_window,
+            peer_address: Some(peer_address),
+        }
+    }
 
+    /// Wait for the server's answer to `start_connect`.
+    fn finish_connect(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        half_open: HalfOpenConnection<RECV_WRS, SEND_WRS, CQ_ELEMENTS, BUFFER_SIZE>,
+        settings: &ConnectionSettings,
+    ) -> Result<(), IoQueueError> {
+        let HalfOpenConnection {
+            resources,
+            cq,
+            qp,
+            recv_window: our_recv_window,
+            peer_address,
+        } = half_open;
         let event = qd.cm.get_cm_event().expect("TODO");
-        assert_eq!(RdmaCmEvent::Established, event.get_event());
+        match event.get_event() {
+            RdmaCmEvent::Established => {}
+            RdmaCmEvent::Rejected | RdmaCmEvent::Unreachable | RdmaCmEvent::ConnectionError => {
+                warn!("Connection request failed with {:?}.", event.get_event());
+                event.ack();
+                return Err(IoQueueError::ConnectionRefused);
+            }
+            other => panic!("Unexpected event while connecting: {:?}", other),
+        }
 
         // Server sent us its send_window. Let's save it somewhere.
         let peer: PeerConnectionData<u64, 1> =
             event.get_private_data().expect("Private data missing!");
-        dbg!(peer);
 
         let cf = ControlFlow::new(
             qp.clone(),
-            pd.allocate_memory::<u64, 1>(),
+            resources.protection_domain().allocate_memory::<u64, 1>(),
             our_recv_window,
             peer,
         );
-        qd.scheduler_handle = Some(self.executor.add_new_connection(cf, qp, pd, cq));
+        let handle =
+            self.executor
+                .add_new_connection(cf, qp, resources, cq, qd.cm.clone(), peer_address);
+        self.executor.apply_settings(handle, settings);
+        qd.scheduler_handle = Some(handle);
+        Ok(())
     }
 
-    fn resolve_address(qd: &mut QueueDescriptor, node: &str, service: &str) {
+    /// Try every address `node:service` resolves to once. Returns whether one of them worked.
+    fn resolve_address(qd: &mut QueueDescriptor, node: &str, service: &str) -> bool {
         info!("{}", function_name!());
 
         // Get address info and resolve route!
-        let addr_info = CommunicationManager::get_address_info(node, service).expect("TODO");
+        let addr_info = match CommunicationManager::get_address_info(node, service) {
+            Ok(addr_info) => addr_info,
+            Err(_) => return false,
+        };
         let mut current = addr_info;
 
-        // TODO: This will fail if the address is never found.
-        let mut address_resolved = false;
         while current != null_mut() {
-            match qd.cm.resolve_address((unsafe { *current }).ai_dst_addr) {
-                Ok(_) => {
-                    address_resolved = true;
-                    break;
-                }
-                Err(_) => {}
+            if qd
+                .cm
+                .resolve_address((unsafe { *current }).ai_dst_addr)
+                .is_ok()
+                && Self::address_resolved(qd)
+            {
+                return true;
             }
 
             unsafe {
                 current = (*current).ai_next;
             }
         }
-        if !address_resolved {
-            panic!("Unable to resolve address {}:{}", node, service);
-        }
-        // Ack address resolution.
+        false
+    }
+
+    /// Wait for the outcome of a `resolve_address` call.
+    fn address_resolved(qd: &mut QueueDescriptor) -> bool {
         let event = qd.cm.get_cm_event().expect("TODO");
-        assert_eq!(RdmaCmEvent::AddressResolved, event.get_event());
+        let resolved = event.get_event() == RdmaCmEvent::AddressResolved;
+        if !resolved {
+            warn!("Address resolution failed with {:?}.", event.get_event());
+        }
         event.ack();
+        resolved
     }
 
     pub fn listen(&mut self, qd: &mut QueueDescriptor) {
@@ -149,11 +481,34 @@ impl<
         qd.cm.listen().expect("TODO");
     }
 
-    /// NOTE: Accept allocates a protection domain and queue descriptor internally for this id.
+    /// NOTE: Accept allocates a protection domain and queue descriptor internally for this id,
+    /// unless `IoQueueConfig::shared_accept_pool` is set, in which case every connection
+    /// accepted from `qd` uses the protection domain and memory pool of the first one.
     /// And acks establishes connection.
     pub fn accept(&mut self, qd: &mut QueueDescriptor) -> QueueDescriptor {
+        self.accept_with(qd, &ConnectionSettings::default())
+    }
+
+    /// Like `accept` but applies `settings` to the accepted connection.
+    pub fn accept_with(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        settings: &ConnectionSettings,
+    ) -> QueueDescriptor {
         info!("{}", function_name!());
 
+        let (connected_id, half_open) = self.start_accept(qd);
+        self.finish_accept(qd, connected_id, half_open, settings)
+    }
+
+    /// Take the next connection request, set up its verbs objects and answer it.
+    fn start_accept(
+        &mut self,
+        qd: &mut QueueDescriptor,
+    ) -> (
+        CommunicationManager,
+        HalfOpenAccept<RECV_WRS, SEND_WRS, CQ_ELEMENTS, BUFFER_SIZE>,
+    ) {
         // Block until connection request arrives.
         let event = qd.cm.get_cm_event().expect("TODO");
         assert_eq!(RdmaCmEvent::ConnectionRequest, event.get_event());
@@ -164,32 +519,91 @@ impl<
             event.get_private_data().expect("Missing private data!");
         event.ack();
 
-        let mut pd = connected_id.allocate_protection_domain().expect("TODO");
+        let resources = match (
+            qd.accept_resources,
+            self.executor.config().shared_accept_pool,
+        ) {
+            (Some(index), _) => self.executor.accept_resources(index),
+            (None, shared) => {
+                let pd = connected_id.allocate_protection_domain().expect("TODO");
+                let resources = self.executor.new_resources(pd, shared.unwrap_or(1));
+                if shared.is_some() {
+                    qd.accept_resources =
+                        Some(self.executor.share_accept_resources(resources.clone()));
+                }
+                resources
+            }
+        };
         let cq = connected_id.create_cq().expect("TODO");
-        let qp = connected_id.create_qp(&pd, &cq);
+        let qp = connected_id.create_qp(&resources.protection_domain(), &cq);
 
         // Now send our connection data to client.
-        let mut recv_window = VolatileRdmaMemory::new(&mut pd);
+        let mut recv_window = VolatileRdmaMemory::new(&mut resources.protection_domain());
 
         // dbg!(our_private_data);
         connected_id
             .accept_with_private_data(&recv_window.as_connection_data())
             .expect("TODO");
+        let half_open = HalfOpenAccept {
+            connection: HalfOpenConnection {
+                resources,
+                cq,
+                qp,
+                recv_window,
+                peer_address: None,
+            },
+            client_private_data,
+        };
+        (connected_id, half_open)
+    }
+
+    /// Wait for the client to confirm the connection `start_accept` answered.
+    fn finish_accept(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        connected_id: CommunicationManager,
+        half_open: HalfOpenAccept<RECV_WRS, SEND_WRS, CQ_ELEMENTS, BUFFER_SIZE>,
+        settings: &ConnectionSettings,
+    ) -> QueueDescriptor {
+        let HalfOpenAccept {
+            connection:
+                HalfOpenConnection {
+                    resources,
+                    cq,
+                    qp,
+                    recv_window,
+                    peer_address,
+                },
+            client_private_data,
+        } = half_open;
         let event = qd.cm.get_cm_event().expect("TODO");
         assert_eq!(RdmaCmEvent::Established, event.get_event());
         event.ack();
 
         let control_flow = ControlFlow::new(
             qp.clone(),
-            pd.allocate_memory(),
+            resources.protection_domain().allocate_memory(),
             recv_window,
             client_private_data,
         );
-        let scheduler_handle = self.executor.add_new_connection(control_flow, qp, pd, cq);
+        let cm = Rc::new(connected_id);
+        let scheduler_handle = self.executor.add_new_connection(
+            control_flow,
+            qp,
+            resources,
+            cq,
+            cm.clone(),
+            peer_address,
+        );
+        self.executor.apply_settings(scheduler_handle, settings);
 
         QueueDescriptor {
-            cm: connected_id,
+            cm,
             scheduler_handle: Some(scheduler_handle),
+            messages: MessageState::default(),
+            rpc: RpcState::default(),
+            remote_regions: Vec::new(),
+            accept_resources: None,
         }
     }
 
@@ -204,52 +618,881 @@ impl<
             .malloc(qd.scheduler_handle.expect("Missing executor handle."))
     }
 
-    pub fn free(&mut self, qd: &mut QueueDescriptor, memory: RdmaMemory<u8, BUFFER_SIZE>) {
+    /// Like `malloc` but zeroes the whole buffer first, so a partially filled buffer can't leak
+    /// a previous payload. This writes all `BUFFER_SIZE` bytes on every call, which is noticeable
+    /// for large buffers; prefer `malloc` on latency-sensitive paths.
+    pub fn malloc_zeroed(&mut self, qd: &mut QueueDescriptor) -> RdmaMemory<u8, BUFFER_SIZE> {
+        trace!("{}", function_name!());
+
+        let mut memory = self.malloc(qd);
+        for byte in memory.as_mut_slice(BUFFER_SIZE).iter_mut() {
+            *byte = 0;
+        }
+        memory.reset_access();
+        memory
+    }
+
+    /// Fails with `DoubleFree` if `memory` was already freed, `ForeignBuffer` if it came from
+    /// another connection's `malloc`, or `WrongInstance` if `qd` belongs to another `IoQueue`.
+    /// All of them hand `memory` back in the `FreeError`.
+    pub fn free(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        memory: RdmaMemory<u8, BUFFER_SIZE>,
+    ) -> Result<(), FreeError<BUFFER_SIZE>> {
         trace!("{}", function_name!());
         // TODO Do proper error handling. This expect means the connection was never properly
         // established via accept or connect. So we never added it to the executor.
         self.executor.free(
             qd.scheduler_handle.expect("Missing executor handle."),
             memory,
-        );
+        )
+    }
+
+    /// Free a batch of buffers at once, e.g. everything returned by `drain_completed`. Fails
+    /// with `ForeignBuffer` before touching the pool if any of them belongs to another
+    /// connection, and with `WrongInstance` if `qd` belongs to another `IoQueue`; both hand the
+    /// whole batch back in the `FreeError`. Fails with `DoubleFree` if some were already freed,
+    /// handing just those back; all others are still returned to the pool.
+    pub fn free_all(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        memory: Vec<RdmaMemory<u8, BUFFER_SIZE>>,
+    ) -> Result<(), FreeError<BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+        self.executor.free_all(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            memory,
+        )
     }
 
     /// We will need to use the lower level ibverbs interface to register UserArrays with
     /// RDMA on behalf of the user.
     /// TODO: If user drops QueueToken we will be pointing to dangling memory... We should reference
     /// count he memory ourselves...
+    /// Fails with `WouldBlock` once `push_queue_capacity` pushes are already waiting for send
+    /// windows, with `NoSendWindows` if `IoQueueConfig::fail_without_send_windows` is set and
+    /// none are available, `ForeignBuffer` if `mem` came from another connection's `malloc`, or
+    /// `EmptyPush` if nothing was written to it. Either way the memory is handed back in the
+    /// `PushError`.
     pub fn push(
         &mut self,
         qd: &mut QueueDescriptor,
         mem: RdmaMemory<u8, BUFFER_SIZE>,
-    ) -> QueueToken {
+    ) -> Result<QueueToken, PushError<BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+        self.push_with_tag(qd, mem, None)
+    }
+
+    /// Like `push` but attaches `tag` to the returned token, see `QueueToken::tag`. Tokens
+    /// rebuilt by `drain_completed` carry it too.
+    pub fn push_tagged(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        mem: RdmaMemory<u8, BUFFER_SIZE>,
+        tag: u64,
+    ) -> Result<QueueToken, PushError<BUFFER_SIZE>> {
         trace!("{}", function_name!());
+        self.push_with_tag(qd, mem, Some(tag))
+    }
 
+    /// Send a copy of `data` without managing a buffer. The copy is recycled as soon as the send
+    /// completes; the completion is kept until the token is waited on (returning
+    /// `CompletedRequest::Sent`) or drained. Use `push_copy_untracked` when you never will.
+    /// Fails with `MessageTooLarge` if `data` doesn't fit in `BUFFER_SIZE`.
+    pub fn push_copy(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        data: &[u8],
+    ) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
         let error = "Passed queue descriptor has no scheduler associated wit it!\
                      You likely passed the connection listener descriptor instead\
                      of the connection descriptor.";
         let handle = qd.scheduler_handle.expect(error);
-        self.executor.push(handle, mem)
+        self.executor.push_copy(handle, &[data])
+    }
+
+    /// Fire-and-forget `push_copy`: no token, and nothing is kept once the send completes.
+    pub fn push_copy_untracked(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        data: &[u8],
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor.push_copy_untracked(handle, &[data])
+    }
+
+    fn push_with_tag(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        mem: RdmaMemory<u8, BUFFER_SIZE>,
+        tag: Option<u64>,
+    ) -> Result<QueueToken, PushError<BUFFER_SIZE>> {
+        let error = "Passed queue descriptor has no scheduler associated wit it!\
+                     You likely passed the connection listener descriptor instead\
+                     of the connection descriptor.";
+        let handle = qd.scheduler_handle.expect(error);
+        self.executor.push(handle, mem, tag)
+    }
+
+    /// Half-close: tell the peer we won't send anything more while still receiving from it.
+    /// Blocks until every earlier push and the end-of-stream marker have been sent. Afterwards
+    /// `push` fails with `SendShutdown`, and once the peer has popped everything we sent its
+    /// pops return `CompletedRequest::EndOfStream`.
+    pub fn shutdown_send(&mut self, qd: &mut QueueDescriptor) -> Result<(), IoQueueError> {
+        info!("{}", function_name!());
+
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        let qt = self.executor.shutdown_send(handle)?;
+        self.complete_fragment(qd, qt)
+    }
+
+    /// Ring the peer's doorbell: one of its pops returns `CompletedRequest::Notify` once no
+    /// received data is left for them to hand out. Needs `IoQueueConfig::notifications` on both
+    /// ends, fails with `InvalidConfig` without it. The doorbell carries no payload, but like any
+    /// send it uses a send window and one of the peer's receive buffers. Its buffer goes back to
+    /// the pool as soon as it has been sent, waiting on the token returns
+    /// `CompletedRequest::Sent`. `pop_typed`, `recv_message`, `recv_call` and `request_reply` skip
+    /// doorbells. Limited like `push` by `max_outstanding` and `fail_without_send_windows`, and
+    /// fails with `WouldBlock` while the memory pool is empty.
+    pub fn notify(&mut self, qd: &mut QueueDescriptor) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor.notify(handle)
+    }
+
+    /// Block until everything pushed on `qd` so far has been posted to the NIC, e.g. while the
+    /// peer is out of receive windows. Fails with `FlushTimedOut` if that takes longer than
+    /// `timeout`, or with the connection's error if it dies first. Posted sends may still be
+    /// in flight; wait on their tokens to know they were delivered.
+    pub fn flush(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        timeout: Duration,
+    ) -> Result<(), IoQueueError> {
+        info!("{}", function_name!());
+        self.executor.flush(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            timeout,
+        )
+    }
+
+    /// Cancel an outstanding request. A push can only be cancelled while it is still queued
+    /// waiting for send windows: its memory is returned to the pool and waiting on `qt` returns
+    /// `CompletedRequest::Error(Cancelled)`. Pushes already posted to the NIC (or already waited
+    /// on) fail with `AlreadyPosted`; wait for them as usual. Cancelling a push twice fails with
+    /// `Cancelled`. Cancelling a pop always succeeds since pops don't hold a receive buffer.
+    pub fn cancel(&mut self, qt: QueueToken) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+        self.executor.cancel(qt)
     }
 
     /// TODO: Bad things will happen if queue token is dropped as the memory registered with
     /// RDMA will be deallocated.
     pub fn pop(&mut self, qd: &mut QueueDescriptor) -> QueueToken {
         trace!("{}", function_name!());
-        self.executor.pop(qd.scheduler_handle.unwrap())
+        self.executor.pop(qd.scheduler_handle.unwrap(), None)
     }
 
-    pub fn wait(&mut self, qt: QueueToken) -> CompletedRequest<u8, BUFFER_SIZE> {
+    /// Like `pop` but attaches `tag` to the returned token. Since any received buffer can
+    /// satisfy any pop, pops returned by `drain_completed` are never tagged.
+    pub fn pop_tagged(&mut self, qd: &mut QueueDescriptor, tag: u64) -> QueueToken {
+        trace!("{}", function_name!());
+        self.executor.pop(qd.scheduler_handle.unwrap(), Some(tag))
+    }
+
+    /// Instead of returning a token, call `callback` with the next received buffer as soon as
+    /// the completion is processed. Callbacks run inside the IoQueue while it is being polled
+    /// (by `wait`, `poll` and friends), so they can't call back into it. Return the buffer to have
+    /// it recycled, or keep it and `free` it later. Callbacks are served before any waiting
+    /// `pop` token, oldest first, and also receive `EndOfStream` and connection errors.
+    pub fn pop_with_callback<F>(&mut self, qd: &mut QueueDescriptor, callback: F)
+    where
+        F: FnOnce(CompletedRequest<u8, BUFFER_SIZE>) -> Option<RdmaMemory<u8, BUFFER_SIZE>>,
+        F: 'static,
+    {
+        trace!("{}", function_name!());
+        self.executor
+            .pop_with_callback(qd.scheduler_handle.unwrap(), Box::new(callback));
+    }
+
+    /// Like `push` but returns a future resolving once the push completes. Dropping the future
+    /// before then cancels the push.
+    pub fn push_async(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        mem: RdmaMemory<u8, BUFFER_SIZE>,
+    ) -> Result<
+        Completion<'_, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE>,
+        PushError<BUFFER_SIZE>,
+    > {
+        let qt = self.push(qd, mem)?;
+        Ok(Completion::new(self, qt))
+    }
+
+    /// Like `pop` but returns a future resolving once data arrives.
+    pub fn pop_async(
+        &mut self,
+        qd: &mut QueueDescriptor,
+    ) -> Completion<'_, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
+        let qt = self.pop(qd);
+        Completion::new(self, qt)
+    }
+
+    /// Push `request`, then wait for both it and the next message from the peer. The request
+    /// buffer is returned to the pool once its push completes; the reply is handed back and should
+    /// be `free`d as usual. Retries while the push queue is full instead of failing with
+    /// `WouldBlock`, but gives up with `FlowControlStalled` once `wait` would warn about a stall,
+    /// see `IoQueueConfig::stall_warning_iterations`. A request that was never queued is handed
+    /// back in the `RequestError`; on any other failure nothing is left outstanding.
+    pub fn request_reply(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        request: RdmaMemory<u8, BUFFER_SIZE>,
+    ) -> Result<RdmaMemory<u8, BUFFER_SIZE>, RequestError<BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        let mut stall = StallCheck::new(&self.executor, handle);
+        let mut request = request;
+        let mut push_qt = loop {
+            match self.push(qd, request) {
+                Ok(qt) => break Some(qt),
+                Err(PushError {
+                    error: IoQueueError::WouldBlock | IoQueueError::NoSendWindows,
+                    memory,
+                }) => {
+                    if stall.stalled(&self.executor, handle) {
+                        return Err(RequestError {
+                            error: IoQueueError::FlowControlStalled,
+                            request: Some(memory),
+                        });
+                    }
+                    request = memory;
+                    self.executor.poll_all_tasks();
+                }
+                Err(PushError { error, memory }) => {
+                    return Err(RequestError {
+                        error,
+                        request: Some(memory),
+                    })
+                }
+            }
+        };
+        let pop_qt = self.pop(qd);
+
+        let mut reply = None;
+        // Drive both tokens together: the reply may well land before our push completion does.
+        let failed = loop {
+            if push_qt.is_none() && reply.is_some() {
+                break None;
+            }
+            if let Some(qt) = push_qt {
+                match self.try_wait(qt) {
+                    Some(CompletedRequest::Push(memory)) => {
+                        push_qt = None;
+                        if let Err(e) = self.free(qd, memory) {
+                            break Some(e.error);
+                        }
+                    }
+                    Some(CompletedRequest::Sent) => push_qt = None,
+                    Some(CompletedRequest::Error(e)) => {
+                        push_qt = None;
+                        break Some(e);
+                    }
+                    Some(CompletedRequest::Pop(..))
+                    | Some(CompletedRequest::Corrupt { .. })
+                    | Some(CompletedRequest::EndOfStream)
+                    | Some(CompletedRequest::Notify) => {
+                        unreachable!("Waited on a push token.")
+                    }
+                    None => {
+                        if stall.stalled(&self.executor, handle) {
+                            break Some(IoQueueError::FlowControlStalled);
+                        }
+                    }
+                }
+            }
+            if reply.is_none() {
+                match self.try_wait(pop_qt) {
+                    Some(CompletedRequest::Pop(memory, _)) => reply = Some(memory),
+                    Some(CompletedRequest::Error(e)) => break Some(e),
+                    Some(CompletedRequest::Corrupt {
+                        memory,
+                        expected,
+                        actual,
+                    }) => {
+                        reply = Some(memory);
+                        break Some(IoQueueError::ChecksumMismatch { expected, actual });
+                    }
+                    Some(CompletedRequest::EndOfStream) => {
+                        break Some(IoQueueError::ConnectionClosed)
+                    }
+                    // Not the reply. Pop tokens take whatever is ready, so keep waiting on it.
+                    Some(CompletedRequest::Notify) => {}
+                    Some(CompletedRequest::Push(_)) | Some(CompletedRequest::Sent) => {
+                        unreachable!("Waited on a pop token.")
+                    }
+                    None => {}
+                }
+            }
+        };
+        match failed {
+            None => Ok(reply.unwrap()),
+            Some(error) => {
+                self.abandon_request(qd, push_qt, pop_qt, reply);
+                Err(RequestError {
+                    error,
+                    request: None,
+                })
+            }
+        }
+    }
+
+    /// Clean up after a failed `request_reply`: take back the request if it is still queued or
+    /// wait for the NIC to be done with it, drop the pop and free whatever reply did arrive.
+    /// Failures along the way are only logged, the caller already has an error to report.
+    fn abandon_request(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        push_qt: Option<QueueToken>,
+        pop_qt: QueueToken,
+        reply: Option<RdmaMemory<u8, BUFFER_SIZE>>,
+    ) {
+        if let Some(qt) = push_qt {
+            // A cancelled push goes straight back to the pool.
+            if let Err(IoQueueError::AlreadyPosted) = self.cancel(qt) {
+                if let CompletedRequest::Push(memory) = self.wait(qt) {
+                    if let Err(e) = self.free(qd, memory) {
+                        warn!("Failed to free the request buffer: {}", e);
+                    }
+                }
+            }
+        }
+        if let Err(e) = self.cancel(pop_qt) {
+            warn!("Failed to cancel the reply pop: {}", e);
+        }
+        if let Some(memory) = reply {
+            if let Err(e) = self.free(qd, memory) {
+                warn!("Failed to free the reply buffer: {}", e);
+            }
+        }
+    }
+
+    /// Push `value` as the whole content of one buffer. Wait on the token and `free` the buffer
+    /// as with `push`. Fails with `MessageTooLarge` if `T` doesn't fit in `BUFFER_SIZE`.
+    pub fn push_typed<T: Pod>(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        value: T,
+    ) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+
+        let bytes = bytemuck::bytes_of(&value);
+        if bytes.len() > BUFFER_SIZE {
+            return Err(IoQueueError::MessageTooLarge);
+        }
+        let mut memory = self.malloc(qd);
+        memory.as_mut_slice(bytes.len()).copy_from_slice(bytes);
+        match self.push(qd, memory) {
+            Ok(qt) => Ok(qt),
+            Err(PushError { error, memory }) => {
+                self.free(qd, memory)?;
+                Err(error)
+            }
+        }
+    }
+
+    /// Wait for the next message and read it as a `T`, freeing its buffer. Fails with
+    /// `LengthMismatch` if the message isn't exactly `size_of::<T>()` bytes.
+    pub fn pop_typed<T: Pod>(&mut self, qd: &mut QueueDescriptor) -> Result<T, IoQueueError> {
+        trace!("{}", function_name!());
+
+        let qt = self.pop(qd);
+        match self.wait_data(qt) {
+            CompletedRequest::Pop(memory, bytes) => {
+                let expected = std::mem::size_of::<T>();
+                let value = if bytes == expected {
+                    Ok(bytemuck::pod_read_unaligned(&memory.as_slice()[..bytes]))
+                } else {
+                    Err(IoQueueError::LengthMismatch {
+                        expected,
+                        actual: bytes,
+                    })
+                };
+                self.free(qd, memory)?;
+                value
+            }
+            CompletedRequest::Error(e) => Err(e),
+            CompletedRequest::Corrupt {
+                memory,
+                expected,
+                actual,
+            } => {
+                self.free(qd, memory)?;
+                Err(IoQueueError::ChecksumMismatch { expected, actual })
+            }
+            CompletedRequest::EndOfStream => Err(IoQueueError::ConnectionClosed),
+            CompletedRequest::Notify => unreachable!("wait_data skips doorbells."),
+            CompletedRequest::Push(_) | CompletedRequest::Sent => {
+                unreachable!("Waited on a pop token.")
+            }
+        }
+    }
+
+    /// Send `request` tagged with a fresh correlation id. Collect the reply with `await_reply`;
+    /// any number of calls may be in flight and their replies may come back in any order. The
+    /// peer reads requests with `recv_call` and answers with `reply`.
+    pub fn call(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        request: &[u8],
+    ) -> Result<CallToken, IoQueueError> {
+        trace!("{}", function_name!());
+
+        let token = qd.rpc.start_call();
+        let header = rpc::write_header(token.correlation_id);
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor
+            .push_copy_untracked(handle, &[&header, request])?;
+        qd.rpc.add_outstanding(token);
+        Ok(token)
+    }
+
+    /// Block until the reply to `token` arrives. Replies to other calls on `qd` popped in the
+    /// meantime are kept for their own `await_reply`. Fails with `UnknownCorrelationId` on a
+    /// reply that matches no outstanding call.
+    pub fn await_reply(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        token: CallToken,
+    ) -> Result<Vec<u8>, IoQueueError> {
         trace!("{}", function_name!());
+
         loop {
-            match self.executor.wait(qt) {
-                None => match self.executor.poll_completion_coroutine(qt) {
-                    None => self.executor.poll_coroutines(qt),
-                    Some(cr) => return cr,
-                },
-                Some(cr) => return cr,
+            if let Some(reply) = qd.rpc.take_reply(token) {
+                return Ok(reply);
+            }
+            let (correlation_id, reply) = self.recv_call(qd)?;
+            qd.rpc.store_reply(correlation_id, reply)?;
+        }
+    }
+
+    /// Server side of `call`: wait for the next request and return it with its correlation id.
+    pub fn recv_call(&mut self, qd: &mut QueueDescriptor) -> Result<(u64, Vec<u8>), IoQueueError> {
+        trace!("{}", function_name!());
+
+        let qt = self.pop(qd);
+        match self.wait_data(qt) {
+            CompletedRequest::Pop(memory, _) => {
+                let message = rpc::read_header(memory.as_slice())
+                    .map(|(correlation_id, payload)| (correlation_id, payload.to_vec()));
+                self.free(qd, memory)?;
+                message
+            }
+            CompletedRequest::Error(e) => Err(e),
+            CompletedRequest::Corrupt {
+                memory,
+                expected,
+                actual,
+            } => {
+                self.free(qd, memory)?;
+                Err(IoQueueError::ChecksumMismatch { expected, actual })
+            }
+            CompletedRequest::EndOfStream => Err(IoQueueError::ConnectionClosed),
+            CompletedRequest::Notify => unreachable!("wait_data skips doorbells."),
+            CompletedRequest::Push(_) | CompletedRequest::Sent => {
+                unreachable!("Waited on a pop token.")
+            }
+        }
+    }
+
+    /// Answer the request `recv_call` returned `correlation_id` for. Like `call` this doesn't
+    /// hand out a push token: the copy is recycled once it has been sent.
+    pub fn reply(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        correlation_id: u64,
+        reply: &[u8],
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+
+        let header = rpc::write_header(correlation_id);
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor.push_copy_untracked(handle, &[&header, reply])
+    }
+
+    /// Send `data` as a single message split across as many buffers as needed. The peer must use
+    /// `recv_message` to read it. Blocks until every fragment has completed. At most half a
+    /// window of fragments is in flight at once, so a huge message neither overruns the push
+    /// queue nor drains the memory pool that receive buffers come from. Fails with
+    /// `MessageTooLarge` beyond `IoQueueConfig::max_message_length`.
+    pub fn push_message(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        data: &[u8],
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+
+        if data.len() > u32::MAX as usize || data.len() > self.executor.config().max_message_length
+        {
+            return Err(IoQueueError::MessageTooLarge);
+        }
+        let payload_size = self.message_payload_size();
+        let max_in_flight = std::cmp::max(1, WINDOW_SIZE / 2);
+        let message_id = qd.messages.next_send_id;
+        qd.messages.next_send_id = message_id.wrapping_add(1);
+
+        let fragments = fragment_count(data.len(), payload_size);
+        let mut outstanding: VecDeque<QueueToken> = VecDeque::with_capacity(max_in_flight);
+        for sequence in 0..fragments {
+            if outstanding.len() == max_in_flight {
+                let qt = outstanding.pop_front().unwrap();
+                self.complete_fragment(qd, qt)?;
             }
+
+            let start = sequence * payload_size;
+            let chunk = &data[start..std::cmp::min(start + payload_size, data.len())];
+            let header = FragmentHeader {
+                message_id,
+                sequence: sequence as u32,
+                total_length: data.len() as u32,
+                is_final: sequence + 1 == fragments,
+            };
+            let mut memory = self.malloc(qd);
+            let buffer = memory.as_mut_slice(HEADER_SIZE + chunk.len());
+            header.write(&mut buffer[..HEADER_SIZE]);
+            buffer[HEADER_SIZE..].copy_from_slice(chunk);
+
+            loop {
+                match self.push(qd, memory) {
+                    Ok(qt) => {
+                        outstanding.push_back(qt);
+                        break;
+                    }
+                    Err(PushError {
+                        error: IoQueueError::WouldBlock | IoQueueError::NoSendWindows,
+                        memory: returned,
+                    }) => {
+                        // Other pushes on this connection filled the queue. Make room by finishing
+                        // our oldest fragment, or just let the connection make progress.
+                        memory = returned;
+                        match outstanding.pop_front() {
+                            Some(qt) => self.complete_fragment(qd, qt)?,
+                            None => self.executor.poll_all_tasks(),
+                        }
+                    }
+                    Err(e) => return Err(e.error),
+                }
+            }
+        }
+
+        while let Some(qt) = outstanding.pop_front() {
+            self.complete_fragment(qd, qt)?;
+        }
+        Ok(())
+    }
+
+    /// Receive the next message sent with `push_message`, reassembled into one buffer. Fragments
+    /// are put back in order regardless of the order their pops complete in.
+    pub fn recv_message(&mut self, qd: &mut QueueDescriptor) -> Result<Vec<u8>, IoQueueError> {
+        trace!("{}", function_name!());
+
+        let payload_size = self.message_payload_size();
+        let max_length = self.executor.config().max_message_length;
+        loop {
+            if let Some(message) = qd.messages.take_next() {
+                return Ok(message);
+            }
+            let qt = self.pop(qd);
+            match self.wait_data(qt) {
+                CompletedRequest::Pop(memory, _) => {
+                    let added =
+                        qd.messages
+                            .add_fragment(memory.as_slice(), payload_size, max_length);
+                    self.free(qd, memory)?;
+                    added?;
+                }
+                CompletedRequest::Error(e) => return Err(e),
+                CompletedRequest::Corrupt {
+                    memory,
+                    expected,
+                    actual,
+                } => {
+                    self.free(qd, memory)?;
+                    return Err(IoQueueError::ChecksumMismatch { expected, actual });
+                }
+                CompletedRequest::EndOfStream => return Err(IoQueueError::ConnectionClosed),
+                CompletedRequest::Notify => unreachable!("wait_data skips doorbells."),
+                CompletedRequest::Push(_) | CompletedRequest::Sent => {
+                    unreachable!("Waited on a pop token.")
+                }
+            }
+        }
+    }
+
+    /// Keep `region` on `qd` to look up later with `remote_regions`, e.g. a region the peer sent
+    /// during setup.
+    pub fn register_remote(&mut self, qd: &mut QueueDescriptor, region: RemoteRegion) {
+        trace!("{}", function_name!());
+        qd.remote_regions.push(region);
+    }
+
+    /// Every region registered on `qd` with `register_remote`, oldest first.
+    pub fn remote_regions<'a>(&self, qd: &'a QueueDescriptor) -> &'a [RemoteRegion] {
+        &qd.remote_regions
+    }
+
+    /// RDMA-write a copy of `data` at `offset` into the peer's `region`. The peer's CPU isn't
+    /// involved: it uses no receive window and nothing shows up on the peer's pops. The copy is
+    /// recycled as soon as the write completes, waiting on the token returns
+    /// `CompletedRequest::Sent`. Fails with `OutOfBounds` if `data` runs past the end of
+    /// `region`, `MessageTooLarge` if it doesn't fit in `BUFFER_SIZE` and `WouldBlock` while the
+    /// send queue is full or the memory pool is empty. Pushes and writes share the send queue.
+    pub fn write_copy(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        region: &RemoteRegion,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<QueueToken, IoQueueError> {
+        trace!("{}", function_name!());
+        let addr = region.address(offset, data.len())?;
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor.write_copy(handle, data, addr, region.rkey)
+    }
+
+    /// Fire-and-forget `write_copy`: no token, and nothing is kept once the write completes.
+    pub fn write_copy_untracked(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        region: &RemoteRegion,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+        let addr = region.address(offset, data.len())?;
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor
+            .write_copy_untracked(handle, data, addr, region.rkey)
+    }
+
+    /// Set up the receiving end of a ring of `RING_SIZE` bytes on `qd`, with the peer calling
+    /// `ring_sender` for the same size. Registers the ring and trades where to write with the
+    /// peer over ordinary pushes and pops, so both ends must call this at the same point in
+    /// their traffic. Both ends fail with `InvalidConfig` if their ring sizes differ.
+    pub fn ring_receiver<const RING_SIZE: usize>(
+        &mut self,
+        qd: &mut QueueDescriptor,
+    ) -> Result<RingReceiver<RING_SIZE>, IoQueueError> {
+        info!("{}", function_name!());
+
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        let protection_domain = self.executor.protection_domain(handle);
+        let (mut ring, mut head) = {
+            let mut pd = protection_domain.borrow_mut();
+            (
+                VolatileRdmaMemory::<u8, RING_SIZE>::new(&mut pd),
+                VolatileRdmaMemory::<u64, 1>::new(&mut pd),
+            )
+        };
+        head.write(&[0]);
+        let [ring_addr, ring_rkey, ring_len] = RemoteRegion::of(&mut ring).to_words();
+        let [head_addr, head_rkey, head_len] = RemoteRegion::of(&mut head).to_words();
+        let qt = self.push_typed(
+            qd,
+            [
+                ring_addr, ring_rkey, ring_len, head_addr, head_rkey, head_len,
+            ],
+        )?;
+        self.complete_fragment(qd, qt)?;
+        let tail_slot = RemoteRegion::from_words(self.pop_typed(qd)?);
+        // An empty tail slot is the sender refusing our ring.
+        if tail_slot.len == 0 {
+            return Err(IoQueueError::InvalidConfig(ring::SIZE_MISMATCH));
+        }
+        Ok(RingReceiver {
+            _ring: ring,
+            address: ring_addr,
+            head,
+            tail_slot,
+            tail: 0,
+            _protection_domain: protection_domain,
+        })
+    }
+
+    /// Set up the sending end of a ring on `qd`, with the peer calling `ring_receiver`.
+    pub fn ring_sender<const RING_SIZE: usize>(
+        &mut self,
+        qd: &mut QueueDescriptor,
+    ) -> Result<RingSender<RING_SIZE>, IoQueueError> {
+        info!("{}", function_name!());
+
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        let [ring_addr, ring_rkey, ring_len, head_addr, head_rkey, head_len] =
+            self.pop_typed::<[u64; 6]>(qd)?;
+        let ring = RemoteRegion::from_words([ring_addr, ring_rkey, ring_len]);
+        let head_slot = RemoteRegion::from_words([head_addr, head_rkey, head_len]);
+        if ring.len != RING_SIZE {
+            // Tell the receiver too, rather than leaving it waiting for our tail slot.
+            let qt = self.push_typed(qd, [0u64; 3])?;
+            self.complete_fragment(qd, qt)?;
+            return Err(IoQueueError::InvalidConfig(ring::SIZE_MISMATCH));
+        }
+        let protection_domain = self.executor.protection_domain(handle);
+        let mut tail = VolatileRdmaMemory::<u64, 1>::new(&mut protection_domain.borrow_mut());
+        tail.write(&[0]);
+        let qt = self.push_typed(qd, RemoteRegion::of(&mut tail).to_words())?;
+        self.complete_fragment(qd, qt)?;
+        Ok(RingSender {
+            ring,
+            head_slot,
+            tail,
+            head: 0,
+            _protection_domain: protection_domain,
+        })
+    }
+
+    /// Append `data` to the ring, wrapping around its end as needed. All of it or nothing
+    /// becomes visible to the receiver: the new head is only written once the data is. Relies on
+    /// the NIC placing writes on a queue pair in order, which RC queue pairs do in practice.
+    /// Fails with `WouldBlock` if the ring doesn't have room for `data` yet (the receiver hasn't
+    /// read far enough) or the send queue is full, and with `MessageTooLarge` if `data` is larger
+    /// than the whole ring.
+    pub fn ring_write<const RING_SIZE: usize>(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        ring: &mut RingSender<RING_SIZE>,
+        data: &[u8],
+    ) -> Result<(), IoQueueError> {
+        trace!("{}", function_name!());
+
+        if data.len() > RING_SIZE {
+            return Err(IoQueueError::MessageTooLarge);
+        }
+        if data.len() > ring.free() {
+            return Err(IoQueueError::WouldBlock);
+        }
+        if data.is_empty() {
+            return Ok(());
+        }
+        for (offset, range) in ring::segments(ring.head, data.len(), RING_SIZE, BUFFER_SIZE) {
+            self.write_copy_untracked(qd, &ring.ring, offset, &data[range])?;
+        }
+        let head = ring.head + data.len() as u64;
+        self.write_copy_untracked(qd, &ring.head_slot, 0, &head.to_ne_bytes())?;
+        ring.head = head;
+        Ok(())
+    }
+
+    /// Take everything written to the ring since the last read, empty if nothing was, and hand
+    /// the space back to the sender. Nothing is consumed if this fails, e.g. with `WouldBlock`
+    /// while the send queue is full.
+    pub fn ring_read<const RING_SIZE: usize>(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        ring: &mut RingReceiver<RING_SIZE>,
+    ) -> Result<Vec<u8>, IoQueueError> {
+        trace!("{}", function_name!());
+
+        let length = ring.available();
+        if length == 0 {
+            return Ok(Vec::new());
+        }
+        let mut data = vec![0; length];
+        for (offset, range) in ring::segments(ring.tail, length, RING_SIZE, usize::MAX) {
+            ring.copy_out(offset, &mut data[range]);
+        }
+        let tail = ring.tail + length as u64;
+        self.write_copy_untracked(qd, &ring.tail_slot, 0, &tail.to_ne_bytes())?;
+        ring.tail = tail;
+        Ok(data)
+    }
+
+    fn message_payload_size(&self) -> usize {
+        let config = self.executor.config();
+        let mut overhead = HEADER_SIZE;
+        if config.checksum {
+            overhead += checksum::CHECKSUM_SIZE;
+        }
+        if config.notifications {
+            overhead += notify::KIND_SIZE;
+        }
+        assert!(
+            BUFFER_SIZE > overhead,
+            "BUFFER_SIZE must be larger than the {} byte fragment header.",
+            overhead
+        );
+        BUFFER_SIZE - overhead
+    }
+
+    fn complete_fragment(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        qt: QueueToken,
+    ) -> Result<(), IoQueueError> {
+        match self.wait(qt) {
+            CompletedRequest::Push(memory) => Ok(self.free(qd, memory)?),
+            CompletedRequest::Sent => Ok(()),
+            CompletedRequest::Error(e) => Err(e),
+            CompletedRequest::Pop(..)
+            | CompletedRequest::Corrupt { .. }
+            | CompletedRequest::EndOfStream
+            | CompletedRequest::Notify => {
+                unreachable!("Waited on a push token.")
+            }
+        }
+    }
+
+    /// `wait` on a pop token, dropping any doorbells from the peer's `notify` along the way.
+    fn wait_data(&mut self, qt: QueueToken) -> CompletedRequest<u8, BUFFER_SIZE> {
+        loop {
+            match self.wait(qt) {
+                CompletedRequest::Notify => {}
+                completed => return completed,
+            }
+        }
+    }
+
+    /// Non-blocking `wait`: makes one pass over `qt`'s connection and returns `None` right away
+    /// if it hasn't completed. Meant for callers running their own event loop.
+    pub fn try_wait(&mut self, qt: QueueToken) -> Option<CompletedRequest<u8, BUFFER_SIZE>> {
+        trace!("{}", function_name!());
+        if let Some(cr) = self.executor.wait(qt) {
+            return Some(cr);
         }
+        if let Some(cr) = self.executor.poll_completion_coroutine(qt) {
+            return Some(cr);
+        }
+        self.executor.poll_coroutines(qt);
+        self.executor.wait(qt)
+    }
+
+    pub fn wait(&mut self, qt: QueueToken) -> CompletedRequest<u8, BUFFER_SIZE> {
+        trace!("{}", function_name!());
+        if !self.executor.is_running(qt.task_id) {
+            let error = self.executor.connection_error(qt.task_id);
+            return CompletedRequest::Error(error.unwrap_or(IoQueueError::ConnectionClosed));
+        }
+        let start = Instant::now();
+        let mut stall = StallCheck::new(&self.executor, qt.task_id);
+        let completed = loop {
+            if let Some(cr) = self.try_wait(qt) {
+                break cr;
+            }
+
+            // Pops are expected to sit idle until the peer sends something, so only pushes
+            // can be stalled by flow control.
+            if let QueueTokenOp::Push { .. } = qt.op {
+                if stall.stalled(&self.executor, qt.task_id) && self.executor.config().stall_error {
+                    break CompletedRequest::Error(IoQueueError::FlowControlStalled);
+                }
+            }
+        };
+        self.executor.record_wait(qt, start.elapsed());
+        completed
         // loop {
         //     match self.executor.wait(qt) {
         //         None => {
@@ -260,23 +1503,203 @@ impl<
         // }
     }
 
-    pub fn get_and_reset_time(&mut self) -> u32 {
-        TIME.with(|time| {
-            let current = *time.borrow_mut();
-            *time.borrow_mut() = 0;
-            current
-        })
+    /// How many more pushes the peer currently has room for. Pushes beyond this are queued
+    /// until it posts more receive buffers, so this is the number to check for admission
+    /// control.
+    pub fn send_windows(&self, qd: &QueueDescriptor) -> u64 {
+        self.executor
+            .send_windows(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    /// How many receive buffers we currently have posted for the peer to send into.
+    pub fn recv_windows(&self, qd: &QueueDescriptor) -> u64 {
+        self.executor
+            .recv_windows(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    /// Time spent on this connection's behalf since it was established or last reset.
+    /// See `ConnectionTiming` for what each interval covers.
+    pub fn timing(&self, qd: &QueueDescriptor) -> ConnectionTiming {
+        self.executor
+            .timing(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    pub fn reset_timing(&mut self, qd: &mut QueueDescriptor) {
+        self.executor
+            .reset_timing(qd.scheduler_handle.expect("Missing executor handle."));
+    }
+
+    /// Exchange `iterations` one byte messages with the peer, e.g. before a benchmark, so
+    /// caches, pages and the receive windows have settled. Then resets `timing` and the counters
+    /// in `stats` so measurements start clean. The peer must call `warmup` with the same
+    /// `iterations` at the same time: every iteration pushes one message and pops one.
+    pub fn warmup(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        iterations: usize,
+    ) -> Result<(), IoQueueError> {
+        info!("{}: {} iterations", function_name!(), iterations);
+
+        for _ in 0..iterations {
+            let mut request = self.malloc(qd);
+            request.as_mut_slice(1)[0] = 0;
+            let reply = match self.request_reply(qd, request) {
+                Ok(reply) => reply,
+                Err(RequestError { error, request }) => {
+                    if let Some(request) = request {
+                        self.free(qd, request)?;
+                    }
+                    return Err(error);
+                }
+            };
+            self.free(qd, reply)?;
+        }
+        let handle = qd.scheduler_handle.expect("Missing executor handle.");
+        self.executor.reset_timing(handle);
+        self.executor.reset_stats(handle);
+        Ok(())
+    }
+
+    /// Every connection that hasn't been shut down, in slot order.
+    pub fn connections(&self) -> impl Iterator<Item = ConnectionRef<'_>> + '_ {
+        self.executor.connections()
+    }
+
+    pub fn stats(&self, qd: &QueueDescriptor) -> ConnectionStats {
+        self.executor
+            .stats(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    /// The queue pair behind `qd`, for verbs this crate doesn't wrap.
+    ///
+    /// # Safety
+    /// The crate owns every completion on the connection's completion queue and assumes it
+    /// posted the work request behind it. Only post RDMA reads and writes, whose successful
+    /// completions are ignored (a failed one still marks the connection dead). A send or
+    /// receive the crate didn't post makes it panic when it completes.
+    /// Pick work ids far above the crate's own, which count up from 0, and below `u64::MAX - 1`:
+    /// a completion with an id `write_copy` is using recycles its buffer, and the top two ids
+    /// belong to keepalive probes and window updates. Don't change the queue pair's state
+    /// either, that breaks flow control and teardown.
+    pub unsafe fn raw_qp(&mut self, qd: &QueueDescriptor) -> &mut QueuePair<RECV_WRS, SEND_WRS> {
+        self.executor
+            .raw_queue_pair(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    /// The protection domain `qd`'s buffers are registered with, e.g. to register more memory
+    /// or bind memory windows. It may be shared with other connections, see
+    /// `IoQueueConfig::shared_accept_pool`. The completion queue isn't exposed since the crate
+    /// polls it and would take any completion it finds there for its own.
+    ///
+    /// # Safety
+    /// Whatever is allocated from it must be dropped before the connection is.
+    pub unsafe fn raw_pd(&self, qd: &QueueDescriptor) -> std::cell::RefMut<'_, ProtectionDomain> {
+        self.executor
+            .raw_protection_domain(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    /// Latency distribution of `qd`'s pushes (from `push` to send completion) or pops (from
+    /// posting a receive buffer to it being filled). `None` unless
+    /// `IoQueueConfig::latency_histograms` is set. Cleared by `reset_stats`.
+    pub fn latency_percentiles(
+        &self,
+        qd: &QueueDescriptor,
+        kind: RequestKind,
+    ) -> Option<Percentiles> {
+        self.executor
+            .latency_percentiles(qd.scheduler_handle.expect("Missing executor handle."), kind)
+    }
+
+    /// Apply every knob in `settings` to an established connection at once, overriding whatever
+    /// the individual `set_*` methods set before.
+    pub fn apply_settings(&mut self, qd: &mut QueueDescriptor, settings: &ConnectionSettings) {
+        self.executor.apply_settings(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            settings,
+        );
+    }
+
+    /// Record which core this connection should be polled from, reported back in `stats`.
+    /// Nothing is pinned yet since all connections are polled by the thread owning the
+    /// `IoQueue`, but together with `ConnectionStats::slot` it lets you reason about where each
+    /// connection's state lives.
+    pub fn set_core_affinity(&mut self, qd: &mut QueueDescriptor, core: Option<usize>) {
+        self.executor
+            .set_core_affinity(qd.scheduler_handle.expect("Missing executor handle."), core);
+    }
+
+    /// Lower the priority of a connection when driving many of them: `wait_any` and `poll` only
+    /// advance it on one of every `poll_every` passes. The default of 1 advances it every pass,
+    /// so e.g. bulk connections can be set to 8 to leave latency-sensitive ones more of the
+    /// loop. Waiting on one of its own tokens with `wait`/`try_wait` always polls it.
+    pub fn set_priority(&mut self, qd: &mut QueueDescriptor, poll_every: usize) {
+        self.executor.set_poll_every(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            poll_every,
+        );
+    }
+
+    /// Post at most `batch` sends per pass of the push coroutine, even when more send windows
+    /// are available, so one busy connection can't hog a shared completion queue. `None` lifts
+    /// the cap. Both this and the receive batch are reported in `stats`.
+    pub fn set_send_batch(&mut self, qd: &mut QueueDescriptor, batch: Option<usize>) {
+        self.executor.set_send_batch(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            batch,
+        );
+    }
+
+    /// Cap how fast this connection sends, in bytes of payload per second. Pushes beyond the
+    /// limit stay queued until the budget refills. `None` removes the limit.
+    pub fn set_rate_limit(&mut self, qd: &mut QueueDescriptor, bytes_per_sec: Option<u64>) {
+        self.executor.set_rate_limit(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            bytes_per_sec,
+        );
+    }
+
+    /// Call `hook` every time this connection's memory pool runs out of buffers for receives,
+    /// see `ConnectionStats::pool_exhausted`. Replaces any previously set hook.
+    pub fn set_pool_exhausted_hook(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        hook: impl FnMut(ConnectionStats) + 'static,
+    ) {
+        self.executor.set_pool_exhausted_hook(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            Box::new(hook),
+        );
+    }
+
+    /// Call `hook` once this connection has seen `threshold` congestion-related completion
+    /// errors (RNR or transport retries exceeded), e.g. to back off an application-level rate
+    /// limiter. Replaces any previously set hook.
+    /// NOTE: On an RC queue pair these errors are fatal, so the first one also fails the
+    /// connection. The counts mostly tell you *why* a connection died.
+    pub fn set_congestion_hook(
+        &mut self,
+        qd: &mut QueueDescriptor,
+        threshold: u64,
+        hook: impl FnMut(ConnectionStats) + 'static,
+    ) {
+        self.executor.set_congestion_hook(
+            qd.scheduler_handle.expect("Missing executor handle."),
+            threshold,
+            Box::new(hook),
+        );
     }
 
     pub fn wait_any(&mut self, qts: &[QueueToken]) -> (usize, CompletedRequest<u8, BUFFER_SIZE>) {
         trace!("{}", function_name!());
 
+        let start = Instant::now();
         let mut pops_checked: bool = false;
         loop {
             for (i, qt) in qts.iter().enumerate() {
                 match qt.op {
                     QueueTokenOp::Push { .. } => {
                         if let Some(completed_op) = self.executor.wait(*qt) {
+                            self.executor.record_wait(*qt, start.elapsed());
                             return (i, completed_op);
                         }
                     }
@@ -285,6 +1708,7 @@ impl<
                             continue;
                         } else {
                             if let Some(completed_op) = self.executor.wait(*qt) {
+                                self.executor.record_wait(*qt, start.elapsed());
                                 return (i, completed_op);
                             } else {
                                 pops_checked = true;
@@ -298,10 +1722,129 @@ impl<
         }
     }
 
+    /// Why `qd`'s connection can't be used anymore, or `None` while it is healthy.
+    /// Descriptors that never got connected count as closed.
+    pub fn connection_error(&self, qd: &QueueDescriptor) -> Option<IoQueueError> {
+        match qd.scheduler_handle {
+            Some(handle) => self.executor.connection_error(handle),
+            None => Some(IoQueueError::ConnectionClosed),
+        }
+    }
+
+    /// Tear down what the executor holds for a connection that has already failed, without the
+    /// CM handshake `disconnect` does.
+    pub(crate) fn abandon(&mut self, qd: &mut QueueDescriptor) {
+        if let Some(handle) = qd.scheduler_handle.take() {
+            if self.executor.is_running(handle) {
+                self.executor.shutdown(handle);
+            }
+        }
+    }
+
+    /// Work requests posted on `qd` whose completion hasn't come back yet. This includes the
+    /// receive buffers posted for incoming messages, not just pushes.
+    pub fn outstanding(&self, qd: &QueueDescriptor) -> usize {
+        self.executor
+            .outstanding(qd.scheduler_handle.expect("Missing executor handle."))
+    }
+
+    /// Debugging aid for a `wait_any` that never returns: where each of `qts` currently is.
+    /// Doesn't poll anything.
+    pub fn pending_report(&self, qts: &[QueueToken]) -> Vec<TokenStatus> {
+        qts.iter()
+            .map(|qt| self.executor.token_status(*qt))
+            .collect()
+    }
+
+    /// Like `wait`, but advances every connection while waiting instead of only `qt`'s, so one
+    /// blocked caller doesn't starve the others in a single-threaded server.
+    pub fn wait_servicing_all(&mut self, qt: QueueToken) -> CompletedRequest<u8, BUFFER_SIZE> {
+        trace!("{}", function_name!());
+
+        let start = Instant::now();
+        loop {
+            if let Some(cr) = self.executor.wait(qt) {
+                self.executor.record_wait(qt, start.elapsed());
+                return cr;
+            }
+            self.executor.poll_all_tasks();
+        }
+    }
+
+    /// Like `wait_any` but returns a future, so it can be awaited from inside an `async fn`
+    /// under whatever runtime the caller uses.
+    pub fn wait_any_async(
+        &mut self,
+        qts: &[QueueToken],
+    ) -> WaitAny<'_, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
+        WaitAny::new(self, qts)
+    }
+
+    /// Future resolving once `qd`'s connection is over: the peer shut down its send direction or
+    /// the connection failed, see `DisconnectReason`. Meant to be raced against other work, e.g.
+    /// with `select!`, so a handler notices a peer that went away.
+    pub fn closed(
+        &mut self,
+        qd: &QueueDescriptor,
+    ) -> Closed<'_, RECV_WRS, SEND_WRS, CQ_ELEMENTS, WINDOW_SIZE, BUFFER_SIZE> {
+        Closed::new(self, qd.scheduler_handle)
+    }
+
+    /// One non-blocking round of `wait_any`: check every token, advance all connections once and
+    /// check again.
+    pub(crate) fn try_wait_any(
+        &mut self,
+        qts: &[QueueToken],
+    ) -> Option<(usize, CompletedRequest<u8, BUFFER_SIZE>)> {
+        for _ in 0..2 {
+            for (i, qt) in qts.iter().enumerate() {
+                if let Some(completed_op) = self.executor.wait(*qt) {
+                    return Some((i, completed_op));
+                }
+            }
+            self.executor.poll_all_tasks();
+        }
+        None
+    }
+
+    /// Disconnect and tear down everything the connection holds. Its outstanding tokens resolve
+    /// to `ConnectionClosed`.
     pub fn disconnect(&mut self, qd: QueueDescriptor) {
         qd.cm.disconnect().unwrap();
         let event = qd.cm.get_cm_event().unwrap();
         assert_eq!(event.get_event(), RdmaCmEvent::Disconnected);
         event.ack();
+        if let Some(handle) = qd.scheduler_handle {
+            self.executor.shutdown(handle);
+        }
+    }
+
+    /// Tear down every connection at once: stop their coroutines, then release queue pairs,
+    /// completion queues, memory pools and protection domains in that order. Every connection is
+    /// disconnected first, so peers see it go away instead of waiting on it. Meant for a clean
+    /// exit, e.g. from a SIGINT handler's flag.
+    pub fn shutdown(mut self) {
+        info!("{}", function_name!());
+        self.executor.shutdown_all();
+    }
+
+    /// Make one pass over `qd`'s connection and hand back everything that has completed, along
+    /// with the token each result belongs to. Cheaper than calling `wait` per token after
+    /// pushing or popping a batch. Memory is returned just like from `wait` and must be `free`d.
+    pub fn drain_completed(
+        &mut self,
+        qd: &mut QueueDescriptor,
+    ) -> impl Iterator<Item = (QueueToken, CompletedRequest<u8, BUFFER_SIZE>)> {
+        trace!("{}", function_name!());
+        self.executor
+            .drain_completed(qd.scheduler_handle.expect("Missing executor handle."))
+            .into_iter()
+    }
+
+    /// Make one pass over every connection without waiting on anything in particular. Meant
+    /// for custom runtimes that drive the queue themselves and check tokens with `try_wait`.
+    pub fn poll(&mut self) {
+        trace!("{}", function_name!());
+        self.executor.poll_all_tasks();
     }
 }
@@ -0,0 +1,138 @@
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use hashbrown::HashSet;
+use rdma_cm::{ProtectionDomain, RdmaMemory};
+
+/// Where a connection gets its buffers from, both for `malloc` and for the receive buffers it
+/// posts. Every connection has its own pool, built by a `MemoryPoolFactory` once the connection's
+/// protection domain exists.
+pub trait MemoryPool<const SIZE: usize> {
+    /// Hand out a buffer, or `None` if the pool is exhausted.
+    fn allocate(&mut self) -> Option<RdmaMemory<u8, SIZE>>;
+    /// Take back a buffer previously handed out by `allocate`. Its access has already been reset.
+    fn recycle(&mut self, memory: RdmaMemory<u8, SIZE>);
+    /// Whether `memory` was allocated by this pool. Used to catch buffers from another
+    /// connection, which are registered with the wrong protection domain.
+    fn owns(&self, memory: &RdmaMemory<u8, SIZE>) -> bool;
+    /// Buffers currently available.
+    fn available(&self) -> usize;
+    /// Whether `memory` is sitting in the pool right now, i.e. freeing it again would hand the
+    /// same region out twice. Pools that can't tell return false, which disables the check.
+    fn is_pooled(&self, _memory: &RdmaMemory<u8, SIZE>) -> bool {
+        false
+    }
+}
+
+/// Builds a connection's pool from its protection domain and the number of buffers it should
+/// hold (`pool_overprovision * WINDOW_SIZE`).
+pub type MemoryPoolFactory<const SIZE: usize> =
+    Box<dyn Fn(&mut ProtectionDomain, usize) -> Box<dyn MemoryPool<SIZE>>>;
+
+pub(crate) type SharedMemoryPool<const SIZE: usize> = Rc<RefCell<Box<dyn MemoryPool<SIZE>>>>;
+
+/// A protection domain and the pool registered with it. Clones share both, so the protection
+/// domain is only deallocated once the last connection (or listener) using it is gone.
+#[derive(Clone)]
+pub(crate) struct ConnectionResources<const SIZE: usize> {
+    // Declared first so the registered memory is dropped before the protection domain.
+    pub memory_pool: SharedMemoryPool<SIZE>,
+    protection_domain: Rc<RefCell<ProtectionDomain>>,
+}
+
+impl<const SIZE: usize> ConnectionResources<SIZE> {
+    pub fn new(
+        protection_domain: ProtectionDomain,
+        memory_pool: Box<dyn MemoryPool<SIZE>>,
+    ) -> ConnectionResources<SIZE> {
+        ConnectionResources {
+            memory_pool: Rc::new(RefCell::new(memory_pool)),
+            protection_domain: Rc::new(RefCell::new(protection_domain)),
+        }
+    }
+
+    pub fn protection_domain(&self) -> RefMut<'_, ProtectionDomain> {
+        self.protection_domain.borrow_mut()
+    }
+
+    pub fn shared_protection_domain(&self) -> Rc<RefCell<ProtectionDomain>> {
+        self.protection_domain.clone()
+    }
+}
+
+/// The default pool: registers all of its buffers up front and hands them out in FIFO order.
+pub struct RegisteredPool<const SIZE: usize> {
+    buffers: VecDeque<RdmaMemory<u8, SIZE>>,
+    /// Start address of every buffer we registered.
+    addresses: HashSet<usize>,
+    /// Start address of every buffer currently in `buffers`.
+    pooled: HashSet<usize>,
+}
+
+impl<const SIZE: usize> RegisteredPool<SIZE> {
+    pub fn new(protection_domain: &mut ProtectionDomain, size: usize) -> RegisteredPool<SIZE> {
+        let buffers: VecDeque<RdmaMemory<u8, SIZE>> =
+            protection_domain.register_chunk(size).into_iter().collect();
+        let addresses: HashSet<usize> = buffers.iter().map(buffer_address).collect();
+        let pooled = addresses.clone();
+        RegisteredPool {
+            buffers,
+            addresses,
+            pooled,
+        }
+    }
+}
+
+impl<const SIZE: usize> MemoryPool<SIZE> for RegisteredPool<SIZE> {
+    fn allocate(&mut self) -> Option<RdmaMemory<u8, SIZE>> {
+        let memory = self.buffers.pop_front()?;
+        self.pooled.remove(&buffer_address(&memory));
+        Some(memory)
+    }
+
+    fn recycle(&mut self, memory: RdmaMemory<u8, SIZE>) {
+        self.pooled.insert(buffer_address(&memory));
+        self.buffers.push_back(memory)
+    }
+
+    fn owns(&self, memory: &RdmaMemory<u8, SIZE>) -> bool {
+        self.addresses.contains(&buffer_address(memory))
+    }
+
+    fn available(&self) -> usize {
+        self.buffers.len()
+    }
+
+    fn is_pooled(&self, memory: &RdmaMemory<u8, SIZE>) -> bool {
+        self.pooled.contains(&buffer_address(memory))
+    }
+}
+
+pub fn default_factory<const SIZE: usize>() -> MemoryPoolFactory<SIZE> {
+    Box::new(|protection_domain, size| {
+        Box::new(RegisteredPool::<SIZE>::new(protection_domain, size))
+    })
+}
+
+/// Touch every page of every buffer in `pool` so the first transfers don't take page faults.
+pub(crate) fn prefault<const SIZE: usize>(pool: &mut dyn MemoryPool<SIZE>) {
+    const PAGE_SIZE: usize = 4096;
+
+    let mut buffers = Vec::with_capacity(pool.available());
+    while let Some(mut memory) = pool.allocate() {
+        for page in memory.as_mut_slice(SIZE).chunks_mut(PAGE_SIZE) {
+            page[0] = 0;
+        }
+        memory.reset_access();
+        buffers.push(memory);
+    }
+    // Same order as before.
+    for memory in buffers {
+        pool.recycle(memory);
+    }
+}
+
+fn buffer_address<const SIZE: usize>(memory: &RdmaMemory<u8, SIZE>) -> usize {
+    memory.as_slice().as_ptr() as usize
+}
@@ -0,0 +1,262 @@
+//! Framing for messages larger than one buffer. Every fragment starts with a `FragmentHeader`
+//! followed by up to `BUFFER_SIZE - HEADER_SIZE` bytes of payload.
+use std::convert::TryInto;
+
+use hashbrown::HashMap;
+
+use crate::error::IoQueueError;
+
+pub const HEADER_SIZE: usize = 16;
+const FINAL_FRAGMENT: u32 = 1;
+
+#[derive(Debug, Copy, Clone)]
+pub struct FragmentHeader {
+    /// Per-connection message counter. Tells fragments of back-to-back messages apart.
+    pub message_id: u32,
+    /// Position of this fragment within its message.
+    pub sequence: u32,
+    /// Length of the whole message, repeated in every fragment.
+    pub total_length: u32,
+    pub is_final: bool,
+}
+
+impl FragmentHeader {
+    pub fn write(&self, buffer: &mut [u8]) {
+        let flags = if self.is_final { FINAL_FRAGMENT } else { 0 };
+        buffer[0..4].copy_from_slice(&self.message_id.to_le_bytes());
+        buffer[4..8].copy_from_slice(&self.sequence.to_le_bytes());
+        buffer[8..12].copy_from_slice(&self.total_length.to_le_bytes());
+        buffer[12..16].copy_from_slice(&flags.to_le_bytes());
+    }
+
+    pub fn read(buffer: &[u8]) -> Result<FragmentHeader, IoQueueError> {
+        if buffer.len() < HEADER_SIZE {
+            return Err(IoQueueError::MalformedFragment);
+        }
+        let field = |i: usize| u32::from_le_bytes(buffer[i..i + 4].try_into().unwrap());
+        Ok(FragmentHeader {
+            message_id: field(0),
+            sequence: field(4),
+            total_length: field(8),
+            is_final: field(12) & FINAL_FRAGMENT != 0,
+        })
+    }
+}
+
+/// How many fragments a message of `length` bytes is split into. Empty messages still take
+/// one fragment so the peer sees them.
+pub fn fragment_count(length: usize, payload_size: usize) -> usize {
+    std::cmp::max(1, length.div_ceil(payload_size))
+}
+
+struct PartialMessage {
+    data: Vec<u8>,
+    /// One bit per fragment, set once it has arrived.
+    received: Vec<u64>,
+    fragments_received: usize,
+    fragments_expected: usize,
+}
+
+/// Per-connection framing state, kept on the `QueueDescriptor`.
+#[derive(Default)]
+pub struct MessageState {
+    pub next_send_id: u32,
+    next_recv_id: u32,
+    /// Fragments may be handed to us in any order, possibly interleaved with the next message's,
+    /// so each message is assembled here by `message_id` until all its fragments are in.
+    partial: HashMap<u32, PartialMessage>,
+}
+
+impl MessageState {
+    /// Copy a received fragment into its message. Messages longer than `max_length` are refused
+    /// before anything is allocated for them, as are fragments we already have.
+    pub fn add_fragment(
+        &mut self,
+        fragment: &[u8],
+        payload_size: usize,
+        max_length: usize,
+    ) -> Result<(), IoQueueError> {
+        let header = FragmentHeader::read(fragment)?;
+        let payload = &fragment[HEADER_SIZE..];
+        let total_length = header.total_length as usize;
+        if total_length > max_length {
+            return Err(IoQueueError::MalformedFragment);
+        }
+        let fragments_expected = fragment_count(total_length, payload_size);
+
+        let offset = header.sequence as usize * payload_size;
+        if offset + payload.len() > total_length
+            || header.sequence as usize >= fragments_expected
+            || header.is_final != (header.sequence as usize + 1 == fragments_expected)
+        {
+            return Err(IoQueueError::MalformedFragment);
+        }
+
+        let partial = self
+            .partial
+            .entry(header.message_id)
+            .or_insert_with(|| PartialMessage {
+                data: vec![0; total_length],
+                received: vec![0; fragments_expected.div_ceil(64)],
+                fragments_received: 0,
+                fragments_expected,
+            });
+        if partial.data.len() != total_length {
+            return Err(IoQueueError::MalformedFragment);
+        }
+        let (word, bit) = (header.sequence as usize / 64, header.sequence % 64);
+        if partial.received[word] & (1 << bit) != 0 {
+            return Err(IoQueueError::MalformedFragment);
+        }
+        partial.received[word] |= 1 << bit;
+        partial.data[offset..offset + payload.len()].copy_from_slice(payload);
+        partial.fragments_received += 1;
+        Ok(())
+    }
+
+    /// Take the next message in order if all of its fragments have arrived.
+    pub fn take_next(&mut self) -> Option<Vec<u8>> {
+        let id = self.next_recv_id;
+        match self.partial.get(&id) {
+            Some(p) if p.fragments_received == p.fragments_expected => {}
+            _ => return None,
+        }
+        self.next_recv_id = self.next_recv_id.wrapping_add(1);
+        self.partial.remove(&id).map(|p| p.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fragment_count, FragmentHeader, MessageState, HEADER_SIZE};
+    use crate::error::IoQueueError;
+
+    const PAYLOAD: usize = 4;
+    const MAX_LENGTH: usize = 64;
+
+    fn fragment(message_id: u32, sequence: u32, data: &[u8]) -> Vec<u8> {
+        let fragments = fragment_count(data.len(), PAYLOAD) as u32;
+        let header = FragmentHeader {
+            message_id,
+            sequence,
+            total_length: data.len() as u32,
+            is_final: sequence + 1 == fragments,
+        };
+        let start = sequence as usize * PAYLOAD;
+        let chunk = &data[start.min(data.len())..(start + PAYLOAD).min(data.len())];
+        let mut buffer = vec![0; HEADER_SIZE + chunk.len()];
+        header.write(&mut buffer[..HEADER_SIZE]);
+        buffer[HEADER_SIZE..].copy_from_slice(chunk);
+        buffer
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = FragmentHeader {
+            message_id: 7,
+            sequence: 3,
+            total_length: 1 << 20,
+            is_final: true,
+        };
+        let mut buffer = [0; HEADER_SIZE];
+        header.write(&mut buffer);
+        let read = FragmentHeader::read(&buffer).unwrap();
+        assert_eq!(read.message_id, 7);
+        assert_eq!(read.sequence, 3);
+        assert_eq!(read.total_length, 1 << 20);
+        assert!(read.is_final);
+    }
+
+    #[test]
+    fn short_header_is_malformed() {
+        assert!(matches!(
+            FragmentHeader::read(&[0; HEADER_SIZE - 1]),
+            Err(IoQueueError::MalformedFragment)
+        ));
+    }
+
+    #[test]
+    fn fragment_counts() {
+        assert_eq!(fragment_count(0, PAYLOAD), 1);
+        assert_eq!(fragment_count(1, PAYLOAD), 1);
+        assert_eq!(fragment_count(PAYLOAD, PAYLOAD), 1);
+        assert_eq!(fragment_count(PAYLOAD + 1, PAYLOAD), 2);
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut messages = MessageState::default();
+        for sequence in [2, 0] {
+            messages
+                .add_fragment(&fragment(0, sequence, &data), PAYLOAD, MAX_LENGTH)
+                .unwrap();
+        }
+        assert_eq!(messages.take_next(), None);
+        messages
+            .add_fragment(&fragment(0, 1, &data), PAYLOAD, MAX_LENGTH)
+            .unwrap();
+        assert_eq!(messages.take_next(), Some(data));
+        assert_eq!(messages.take_next(), None);
+    }
+
+    #[test]
+    fn messages_come_out_in_order() {
+        let mut messages = MessageState::default();
+        messages
+            .add_fragment(&fragment(1, 0, b"two"), PAYLOAD, MAX_LENGTH)
+            .unwrap();
+        assert_eq!(messages.take_next(), None);
+        messages
+            .add_fragment(&fragment(0, 0, b"one"), PAYLOAD, MAX_LENGTH)
+            .unwrap();
+        assert_eq!(messages.take_next(), Some(b"one".to_vec()));
+        assert_eq!(messages.take_next(), Some(b"two".to_vec()));
+    }
+
+    #[test]
+    fn duplicate_fragment_is_malformed() {
+        let data: Vec<u8> = (0..8).collect();
+        let mut messages = MessageState::default();
+        let first = fragment(0, 0, &data);
+        messages.add_fragment(&first, PAYLOAD, MAX_LENGTH).unwrap();
+        assert!(matches!(
+            messages.add_fragment(&first, PAYLOAD, MAX_LENGTH),
+            Err(IoQueueError::MalformedFragment)
+        ));
+        // The duplicate must not count towards completing the message.
+        assert_eq!(messages.take_next(), None);
+        messages
+            .add_fragment(&fragment(0, 1, &data), PAYLOAD, MAX_LENGTH)
+            .unwrap();
+        assert_eq!(messages.take_next(), Some(data));
+    }
+
+    #[test]
+    fn oversized_message_is_malformed() {
+        let data = vec![0; MAX_LENGTH + 1];
+        let mut messages = MessageState::default();
+        assert!(matches!(
+            messages.add_fragment(&fragment(0, 0, &data), PAYLOAD, MAX_LENGTH),
+            Err(IoQueueError::MalformedFragment)
+        ));
+    }
+
+    #[test]
+    fn out_of_range_sequence_is_malformed() {
+        let data: Vec<u8> = (0..8).collect();
+        let mut bad = fragment(0, 1, &data);
+        FragmentHeader {
+            message_id: 0,
+            sequence: 2,
+            total_length: data.len() as u32,
+            is_final: true,
+        }
+        .write(&mut bad[..HEADER_SIZE]);
+        let mut messages = MessageState::default();
+        assert!(matches!(
+            messages.add_fragment(&bad, PAYLOAD, MAX_LENGTH),
+            Err(IoQueueError::MalformedFragment)
+        ));
+    }
+}
@@ -0,0 +1,35 @@
+//! Optional one byte header on every buffer sent, see `IoQueueConfig::notifications`. It tells
+//! data apart from the doorbells sent by `IoQueue::notify`, which carry nothing but the header.
+//! It goes in front of the checksum, so doorbells don't need one.
+use rdma_cm::RdmaMemory;
+
+pub const KIND_SIZE: usize = 1;
+pub const DATA: u8 = 0;
+pub const NOTIFY: u8 = 1;
+
+/// Shift the payload over and write `kind` in front of it. Returns false if the buffer has no
+/// room left for it.
+pub fn prepend<const SIZE: usize>(memory: &mut RdmaMemory<u8, SIZE>, kind: u8) -> bool {
+    let length = memory.accessed();
+    if length + KIND_SIZE > SIZE {
+        return false;
+    }
+    let buffer = memory.as_mut_slice(length + KIND_SIZE);
+    buffer.copy_within(0..length, KIND_SIZE);
+    buffer[0] = kind;
+    true
+}
+
+/// Remove the header written by `prepend` and return it, leaving just the payload. `None` for
+/// an empty buffer.
+pub fn strip<const SIZE: usize>(memory: &mut RdmaMemory<u8, SIZE>) -> Option<u8> {
+    let length = memory.accessed();
+    if length < KIND_SIZE {
+        return None;
+    }
+    let buffer = memory.as_mut_slice(length);
+    let kind = buffer[0];
+    buffer.copy_within(KIND_SIZE..length, 0);
+    memory.initialize_length(length - KIND_SIZE);
+    Some(kind)
+}
@@ -0,0 +1,78 @@
+//! Token bucket used by `push_coroutine` to cap a connection's send rate, see
+//! `IoQueue::set_rate_limit`.
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+pub(crate) struct TokenBucket {
+    bytes_per_sec: u64,
+    /// Most tokens the bucket holds, i.e. the largest burst we allow after being idle.
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Starts out full. `capacity` is raised to `bytes_per_sec` so a full second's worth can
+    /// always be sent in one go.
+    pub fn new(bytes_per_sec: u64, capacity: usize) -> TokenBucket {
+        let capacity = (capacity as f64).max(bytes_per_sec as f64);
+        TokenBucket {
+            bytes_per_sec,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take tokens for as many of `lengths` as the bucket allows, in order. Returns how many
+    /// were admitted.
+    pub fn admit(&mut self, lengths: impl Iterator<Item = usize>) -> usize {
+        self.refill();
+        let mut admitted = 0;
+        for length in lengths {
+            if self.tokens < length as f64 {
+                break;
+            }
+            self.tokens -= length as f64;
+            admitted += 1;
+        }
+        admitted
+    }
+}
+
+pub(crate) type SharedRateLimit = Rc<RefCell<Option<TokenBucket>>>;
+
+/// Pending until the bucket holds at least `bytes` tokens, or the limit is removed.
+pub(crate) struct Refilled {
+    pub rate_limit: SharedRateLimit,
+    pub bytes: usize,
+}
+
+impl Future for Refilled {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.rate_limit.borrow_mut().as_mut() {
+            None => Poll::Ready(()),
+            Some(bucket) => {
+                bucket.refill();
+                if bucket.tokens >= self.bytes as f64 {
+                    Poll::Ready(())
+                } else {
+                    Poll::Pending
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Handles on memory a peer registered for one-sided writes, see `IoQueue::write_copy`.
+use std::mem::size_of;
+
+use rdma_cm::{PostSendOpcode, VolatileRdmaMemory};
+
+use crate::error::IoQueueError;
+
+/// Memory registered for RDMA writes: where it starts, the rkey it was registered under and how
+/// many bytes long it is. Make one with `RemoteRegion::of` for memory registered with
+/// `IoQueue::raw_pd` and hand it to the peer however your protocol likes, e.g. as a message
+/// during setup. The peer can keep it on the connection with `IoQueue::register_remote`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RemoteRegion {
+    pub addr: u64,
+    pub rkey: u32,
+    pub len: usize,
+}
+
+impl RemoteRegion {
+    /// The region covering all of `memory`. It stays valid for as long as `memory` lives.
+    pub fn of<T: Copy, const N: usize>(memory: &mut VolatileRdmaMemory<T, N>) -> RemoteRegion {
+        match memory.as_connection_data().as_rdma_write() {
+            PostSendOpcode::RdmaWrite(addr, rkey) => RemoteRegion {
+                addr,
+                rkey,
+                len: N * size_of::<T>(),
+            },
+            PostSendOpcode::Send => unreachable!("as_rdma_write always returns a write."),
+        }
+    }
+
+    /// Address of `length` bytes at `offset` into the region. Fails with `OutOfBounds` unless
+    /// they all fit.
+    pub(crate) fn address(&self, offset: usize, length: usize) -> Result<u64, IoQueueError> {
+        match offset.checked_add(length) {
+            Some(end) if end <= self.len => Ok(self.addr + offset as u64),
+            _ => Err(IoQueueError::OutOfBounds {
+                requested: offset.saturating_add(length),
+                available: self.len,
+            }),
+        }
+    }
+
+    /// How `ring_receiver` and `ring_sender` send regions to each other.
+    pub(crate) fn to_words(self) -> [u64; 3] {
+        [self.addr, self.rkey as u64, self.len as u64]
+    }
+
+    pub(crate) fn from_words([addr, rkey, len]: [u64; 3]) -> RemoteRegion {
+        RemoteRegion {
+            addr,
+            rkey: rkey as u32,
+            len: len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGION: RemoteRegion = RemoteRegion {
+        addr: 0x1000,
+        rkey: 7,
+        len: 16,
+    };
+
+    #[test]
+    fn address_within_region() {
+        assert_eq!(REGION.address(0, 16), Ok(0x1000));
+        assert_eq!(REGION.address(12, 4), Ok(0x100c));
+        assert_eq!(REGION.address(16, 0), Ok(0x1010));
+    }
+
+    #[test]
+    fn address_past_the_end() {
+        assert_eq!(
+            REGION.address(12, 5),
+            Err(IoQueueError::OutOfBounds {
+                requested: 17,
+                available: 16
+            })
+        );
+        assert_eq!(
+            REGION.address(usize::MAX, 1),
+            Err(IoQueueError::OutOfBounds {
+                requested: usize::MAX,
+                available: 16
+            })
+        );
+    }
+
+    #[test]
+    fn words_round_trip() {
+        assert_eq!(RemoteRegion::from_words(REGION.to_words()), REGION);
+    }
+}
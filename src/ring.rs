@@ -0,0 +1,130 @@
+//! One-directional byte ring in the receiver's memory that the sender fills with RDMA writes,
+//! see `IoQueue::ring_sender` and `IoQueue::ring_receiver`. No receives are posted for it: the
+//! sender writes the data followed by its new head into the receiver's memory, the receiver
+//! reads up to the head and writes its tail back into the sender's memory so the sender knows
+//! how much room is left. Head and tail count bytes ever written and read, so they never wrap.
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+use std::sync::atomic::{fence, Ordering};
+
+use rdma_cm::{ProtectionDomain, VolatileRdmaMemory};
+
+use crate::remote_region::RemoteRegion;
+
+/// What `ring_sender` and `ring_receiver` fail with when their `RING_SIZE`s differ.
+pub(crate) const SIZE_MISMATCH: &str = "ring size differs from the peer's";
+
+/// Sending end of a ring, made by `IoQueue::ring_sender`. Write to it with `IoQueue::ring_write`.
+/// Keeps the connection's protection domain alive, so it may outlive the connection.
+pub struct RingSender<const RING_SIZE: usize> {
+    pub(crate) ring: RemoteRegion,
+    pub(crate) head_slot: RemoteRegion,
+    /// The receiver writes its tail back here.
+    pub(crate) tail: VolatileRdmaMemory<u64, 1>,
+    pub(crate) head: u64,
+    // Declared last so `tail` is deregistered before the protection domain can go.
+    pub(crate) _protection_domain: Rc<RefCell<ProtectionDomain>>,
+}
+
+impl<const RING_SIZE: usize> RingSender<RING_SIZE> {
+    /// Bytes that can be written before the receiver has to catch up.
+    pub fn free(&self) -> usize {
+        RING_SIZE - (self.head - self.tail.read()[0]) as usize
+    }
+}
+
+/// Receiving end of a ring, made by `IoQueue::ring_receiver`. Read from it with
+/// `IoQueue::ring_read`. Keeps the connection's protection domain alive, so it may outlive the
+/// connection; the peer can't write into it once the connection is gone.
+pub struct RingReceiver<const RING_SIZE: usize> {
+    /// Only read through `address`.
+    pub(crate) _ring: VolatileRdmaMemory<u8, RING_SIZE>,
+    /// Where `_ring` starts. Registered memory is addressed by its virtual address, so this is
+    /// also what the sender writes to.
+    pub(crate) address: u64,
+    /// The sender writes its head here after the data.
+    pub(crate) head: VolatileRdmaMemory<u64, 1>,
+    pub(crate) tail_slot: RemoteRegion,
+    pub(crate) tail: u64,
+    // Declared last so the ring is deregistered before the protection domain can go.
+    pub(crate) _protection_domain: Rc<RefCell<ProtectionDomain>>,
+}
+
+impl<const RING_SIZE: usize> RingReceiver<RING_SIZE> {
+    /// Bytes written by the sender that haven't been read yet.
+    pub fn available(&self) -> usize {
+        let head = self.head.read()[0];
+        // Reads of the data it covers mustn't happen before the head is read.
+        fence(Ordering::Acquire);
+        (head - self.tail) as usize
+    }
+
+    /// Copy the ring's bytes at `offset` into `into`, straight out of the registered memory.
+    /// Only sound for bytes below the head, which the sender won't touch until we move our tail
+    /// past them.
+    pub(crate) fn copy_out(&self, offset: usize, into: &mut [u8]) {
+        assert!(
+            offset + into.len() <= RING_SIZE,
+            "Read past the end of the ring."
+        );
+        // Safety: the range is inside `_ring`, which lives as long as `self`, and the NIC isn't
+        // writing to it, see above.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                (self.address as *const u8).add(offset),
+                into.as_mut_ptr(),
+                into.len(),
+            )
+        }
+    }
+}
+
+/// Split `length` bytes starting at ring position `position` into pieces that neither run past
+/// the end of the ring nor exceed `max` bytes. Returns each piece's offset into the ring and the
+/// range of the bytes it holds.
+pub(crate) fn segments(
+    position: u64,
+    length: usize,
+    ring_size: usize,
+    max: usize,
+) -> Vec<(usize, Range<usize>)> {
+    let mut pieces = Vec::new();
+    let mut done = 0;
+    while done < length {
+        let offset = ((position + done as u64) % ring_size as u64) as usize;
+        let piece = (length - done).min(ring_size - offset).min(max);
+        pieces.push((offset, done..done + piece));
+        done += piece;
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_fit_in_ring() {
+        assert_eq!(segments(3, 4, 16, 16), vec![(3, 0..4)]);
+        assert_eq!(segments(16 * 5 + 3, 4, 16, 16), vec![(3, 0..4)]);
+    }
+
+    #[test]
+    fn segments_wrap_around() {
+        assert_eq!(segments(14, 5, 16, 16), vec![(14, 0..2), (0, 2..5)]);
+    }
+
+    #[test]
+    fn segments_respect_max() {
+        assert_eq!(
+            segments(14, 16, 16, 4),
+            vec![(14, 0..2), (0, 2..6), (4, 6..10), (8, 10..14), (12, 14..16)]
+        );
+    }
+
+    #[test]
+    fn nothing_to_write() {
+        assert!(segments(5, 0, 16, 16).is_empty());
+    }
+}
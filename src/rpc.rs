@@ -0,0 +1,74 @@
+//! Request/response matching for `IoQueue::call`. Every request and reply starts with the
+//! call's correlation id, so replies can be matched to calls no matter what order they arrive in.
+use std::convert::TryInto;
+
+use hashbrown::{HashMap, HashSet};
+
+use crate::error::IoQueueError;
+
+pub const RPC_HEADER_SIZE: usize = 8;
+
+/// A call made with `IoQueue::call`. Pass it to `await_reply` on the same connection.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CallToken {
+    pub(crate) correlation_id: u64,
+}
+
+impl CallToken {
+    /// The id sent in front of the request. A server hands it back to `reply`.
+    pub fn correlation_id(&self) -> u64 {
+        self.correlation_id
+    }
+}
+
+/// Per-connection call state, kept on the `QueueDescriptor`.
+#[derive(Default)]
+pub struct RpcState {
+    next_correlation_id: u64,
+    /// Calls nobody has collected the reply for yet.
+    outstanding: HashSet<u64>,
+    /// Replies that arrived while waiting for a different call.
+    replies: HashMap<u64, Vec<u8>>,
+}
+
+impl RpcState {
+    pub fn start_call(&mut self) -> CallToken {
+        let correlation_id = self.next_correlation_id;
+        self.next_correlation_id += 1;
+        CallToken { correlation_id }
+    }
+
+    /// Only once the request has actually been pushed.
+    pub fn add_outstanding(&mut self, token: CallToken) {
+        self.outstanding.insert(token.correlation_id);
+    }
+
+    pub fn take_reply(&mut self, token: CallToken) -> Option<Vec<u8>> {
+        let reply = self.replies.remove(&token.correlation_id)?;
+        self.outstanding.remove(&token.correlation_id);
+        Some(reply)
+    }
+
+    /// Hold on to a reply until its call is awaited. Fails for replies to calls we never made
+    /// or already collected.
+    pub fn store_reply(&mut self, correlation_id: u64, reply: Vec<u8>) -> Result<(), IoQueueError> {
+        if !self.outstanding.contains(&correlation_id) {
+            return Err(IoQueueError::UnknownCorrelationId(correlation_id));
+        }
+        self.replies.insert(correlation_id, reply);
+        Ok(())
+    }
+}
+
+pub fn write_header(correlation_id: u64) -> [u8; RPC_HEADER_SIZE] {
+    correlation_id.to_le_bytes()
+}
+
+/// Split a received request or reply into its correlation id and payload.
+pub fn read_header(message: &[u8]) -> Result<(u64, &[u8]), IoQueueError> {
+    if message.len() < RPC_HEADER_SIZE {
+        return Err(IoQueueError::MalformedFragment);
+    }
+    let correlation_id = u64::from_le_bytes(message[..RPC_HEADER_SIZE].try_into().unwrap());
+    Ok((correlation_id, &message[RPC_HEADER_SIZE..]))
+}
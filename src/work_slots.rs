@@ -0,0 +1,93 @@
+//! Storage for posted work requests by work id, see `IoQueueConfig::work_slots`.
+use hashbrown::HashMap;
+
+/// Work ids are handed out sequentially and mostly complete in order, so with enough slots
+/// `work_id % slots.len()` is almost always free by the time it comes around again. An id whose
+/// slot still holds an older request goes to `overflow` instead, so a receive buffer that stays
+/// posted for a long time never gets overwritten.
+pub struct WorkSlots<T> {
+    slots: Vec<Option<(u64, T)>>,
+    overflow: HashMap<u64, T>,
+    len: usize,
+}
+
+impl<T> WorkSlots<T> {
+    /// `slots` of 0 keeps everything in the hash map.
+    pub fn new(slots: usize) -> WorkSlots<T> {
+        let overflow_capacity = if slots == 0 { 1000 } else { 0 };
+        WorkSlots {
+            slots: (0..slots).map(|_| None).collect(),
+            overflow: HashMap::with_capacity(overflow_capacity),
+            len: 0,
+        }
+    }
+
+    /// Returns the previous value stored for `work_id`, if any.
+    pub fn insert(&mut self, work_id: u64, value: T) -> Option<T> {
+        if !self.slots.is_empty() && !self.overflow.contains_key(&work_id) {
+            let index = self.index(work_id);
+            match &mut self.slots[index] {
+                slot @ None => {
+                    *slot = Some((work_id, value));
+                    self.len += 1;
+                    return None;
+                }
+                Some((id, previous)) if *id == work_id => {
+                    return Some(std::mem::replace(previous, value));
+                }
+                Some(_) => {}
+            }
+        }
+        let previous = self.overflow.insert(work_id, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, work_id: &u64) -> Option<T> {
+        if !self.slots.is_empty() {
+            let index = self.index(*work_id);
+            if matches!(self.slots[index], Some((id, _)) if id == *work_id) {
+                self.len -= 1;
+                return self.slots[index].take().map(|(_, value)| value);
+            }
+        }
+        let removed = self.overflow.remove(work_id);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    pub fn contains_key(&self, work_id: &u64) -> bool {
+        if !self.slots.is_empty() {
+            if let Some((id, _)) = self.slots[self.index(*work_id)] {
+                if id == *work_id {
+                    return true;
+                }
+            }
+        }
+        self.overflow.contains_key(work_id)
+    }
+
+    /// Remove and return every stored value, in no particular order.
+    pub fn take_all(&mut self) -> Vec<T> {
+        let mut values: Vec<T> = self
+            .slots
+            .iter_mut()
+            .filter_map(|slot| slot.take().map(|(_, value)| value))
+            .collect();
+        values.extend(self.overflow.drain().map(|(_, value)| value));
+        self.len = 0;
+        values
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    fn index(&self, work_id: u64) -> usize {
+        (work_id % self.slots.len() as u64) as usize
+    }
+}
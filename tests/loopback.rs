@@ -0,0 +1,441 @@
+//! Loopback test over a software RDMA device. Needs soft-RoCE (rxe) set up on an interface, e.g.
+//! `rdma link add rxe0 type rxe netdev eth0`, and that interface's address in
+//! `IO_QUEUE_RDMA_TEST_ADDR`:
+//!
+//! IO_QUEUE_RDMA_TEST_ADDR=10.0.0.1 cargo test --features soft-roce
+#![cfg(feature = "soft-roce")]
+
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use io_queue_rdma::{
+    leaked_teardowns, BufferExt, CompletedRequest, DisconnectReason, IoQueue, IoQueueConfig,
+    IoQueueError, RemoteRegion,
+};
+use nix::sys::socket::{InetAddr, SockAddr};
+use rdma_cm::VolatileRdmaMemory;
+
+const PORT: &str = "4545";
+const TEARDOWN_PORT: &str = "4546";
+const PAIR_PORT: &str = "4547";
+const EMPTY_PUSH_PORT: &str = "4548";
+const RING_PORT: &str = "4549";
+const WRITE_PORT: &str = "4550";
+const NOTIFY_PORT: &str = "4551";
+const SHUTDOWN_PORT: &str = "4552";
+const RING_TEARDOWN_PORT: &str = "4553";
+const CLOSED_PORT: &str = "4554";
+
+fn test_address() -> String {
+    std::env::var("IO_QUEUE_RDMA_TEST_ADDR")
+        .expect("Set IO_QUEUE_RDMA_TEST_ADDR to the address of a soft-RoCE interface.")
+}
+
+#[test]
+fn push_pop_loopback() {
+    let ip_address = test_address();
+    let address: SocketAddr = format!("{}:{}", ip_address, PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+    let payload = [1u8, 2, 3, 4];
+
+    let (listening, is_listening) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+        let mut listening_qd = io_queue.socket();
+        io_queue
+            .bind(
+                &mut listening_qd,
+                &SockAddr::new_inet(InetAddr::from_std(&address)),
+            )
+            .unwrap();
+        io_queue.listen(&mut listening_qd);
+        listening.send(()).unwrap();
+
+        let mut connected_qd = io_queue.accept(&mut listening_qd);
+        let qt = io_queue.pop(&mut connected_qd);
+        let memory = io_queue.wait(qt).pop_op();
+        let received = memory.as_slice().to_vec();
+        io_queue.free(&mut connected_qd, memory).unwrap();
+        received
+    });
+
+    is_listening.recv().unwrap();
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut connection = io_queue.socket();
+    io_queue
+        .connect(&mut connection, &ip_address, PORT)
+        .expect("Unable to connect.");
+
+    let mut memory = io_queue.malloc(&mut connection);
+    memory.as_mut_slice(payload.len()).copy_from_slice(&payload);
+    let qt = io_queue.push(&mut connection, memory).unwrap();
+    let memory = io_queue.wait(qt).push_op();
+    assert_eq!(memory.len(), payload.len());
+    io_queue.free(&mut connection, memory).unwrap();
+
+    assert_eq!(server.join().unwrap(), payload);
+}
+
+/// Drop the client while a push and its receive window are still posted, so its buffers, queue
+/// pair, completion queue and protection domain are torn down with work in flight. Teardown has
+/// to let the push finish first: the server still gets it, and nothing is leaked.
+#[test]
+fn drop_with_outstanding_work() {
+    let ip_address = test_address();
+    let address: SocketAddr = format!("{}:{}", ip_address, TEARDOWN_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+    let payload = [1u8, 2, 3, 4];
+
+    let (listening, is_listening) = mpsc::channel();
+    let (dropped, is_dropped) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+        let mut listening_qd = io_queue.socket();
+        io_queue
+            .bind(
+                &mut listening_qd,
+                &SockAddr::new_inet(InetAddr::from_std(&address)),
+            )
+            .unwrap();
+        io_queue.listen(&mut listening_qd);
+        listening.send(()).unwrap();
+
+        let mut connected_qd = io_queue.accept(&mut listening_qd);
+        // Don't look at the connection until the client has dropped its end.
+        is_dropped.recv().unwrap();
+        let qt = io_queue.pop(&mut connected_qd);
+        let memory = io_queue.wait(qt).pop_op();
+        let received = memory.as_slice().to_vec();
+        io_queue.free(&mut connected_qd, memory).unwrap();
+        received
+    });
+
+    is_listening.recv().unwrap();
+    let leaked = leaked_teardowns();
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut connection = io_queue.socket();
+    io_queue
+        .connect(&mut connection, &ip_address, TEARDOWN_PORT)
+        .expect("Unable to connect.");
+
+    let mut memory = io_queue.malloc(&mut connection);
+    memory.as_mut_slice(payload.len()).copy_from_slice(&payload);
+    io_queue.push(&mut connection, memory).unwrap();
+    drop(connection);
+    drop(io_queue);
+    dropped.send(()).unwrap();
+    assert_eq!(
+        leaked_teardowns(),
+        leaked,
+        "Teardown gave up on the push and leaked the connection's memory."
+    );
+
+    assert_eq!(server.join().unwrap(), payload);
+}
+
+/// Shut the client down while a push is still posted. Its completion has to be drained even
+/// though the connection's coroutines have already stopped, so nothing is leaked.
+#[test]
+fn shutdown_with_push_posted() {
+    let ip_address = test_address();
+    let address: SocketAddr = format!("{}:{}", ip_address, SHUTDOWN_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+
+    let (listening, is_listening) = mpsc::channel();
+    let (shut_down, is_shut_down) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+        let mut listening_qd = io_queue.socket();
+        io_queue
+            .bind(
+                &mut listening_qd,
+                &SockAddr::new_inet(InetAddr::from_std(&address)),
+            )
+            .unwrap();
+        io_queue.listen(&mut listening_qd);
+        listening.send(()).unwrap();
+
+        let _connected_qd = io_queue.accept(&mut listening_qd);
+        is_shut_down.recv().unwrap();
+    });
+
+    is_listening.recv().unwrap();
+    let leaked = leaked_teardowns();
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut connection = io_queue.socket();
+    io_queue
+        .connect(&mut connection, &ip_address, SHUTDOWN_PORT)
+        .expect("Unable to connect.");
+
+    io_queue.push_copy(&mut connection, &[1, 2, 3, 4]).unwrap();
+    io_queue
+        .flush(&mut connection, Duration::from_secs(1))
+        .expect("Push was never posted.");
+    io_queue.shutdown();
+    assert_eq!(
+        leaked_teardowns(),
+        leaked,
+        "Shutdown gave up on the posted push and leaked the connection's memory."
+    );
+    shut_down.send(()).unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn connected_pair_in_one_queue() {
+    let address: SocketAddr = format!("{}:{}", test_address(), PAIR_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+    let payload = [5u8, 6, 7];
+
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut pair = io_queue
+        .connected_pair(&SockAddr::new_inet(InetAddr::from_std(&address)))
+        .expect("Unable to connect to ourselves.");
+
+    let push_qt = io_queue.push_copy(&mut pair.client, &payload).unwrap();
+    let pop_qt = io_queue.pop(&mut pair.server);
+    let memory = io_queue.wait(pop_qt).pop_op();
+    assert_eq!(memory.as_slice(), payload);
+    io_queue.free(&mut pair.server, memory).unwrap();
+    io_queue.wait(push_qt);
+}
+
+/// `closed` waits for everything sent before the peer's shutdown to be popped.
+#[test]
+fn closed_after_peer_shutdown() {
+    let address: SocketAddr = format!("{}:{}", test_address(), CLOSED_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut pair = io_queue
+        .connected_pair(&SockAddr::new_inet(InetAddr::from_std(&address)))
+        .expect("Unable to connect to ourselves.");
+
+    io_queue.push_copy(&mut pair.client, &[3]).unwrap();
+    io_queue.shutdown_send(&mut pair.client).unwrap();
+
+    let pop_qt = io_queue.pop(&mut pair.server);
+    let memory = io_queue.wait(pop_qt).pop_op();
+    assert_eq!(memory.as_slice(), [3]);
+    io_queue.free(&mut pair.server, memory).unwrap();
+    assert_eq!(
+        futures::executor::block_on(io_queue.closed(&pair.server)),
+        DisconnectReason::PeerShutdown
+    );
+}
+
+/// Zero length sends mean end-of-stream, so an empty push must be refused rather than
+/// half-closing the peer.
+#[test]
+fn empty_push_does_not_half_close() {
+    let address: SocketAddr = format!("{}:{}", test_address(), EMPTY_PUSH_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut pair = io_queue
+        .connected_pair(&SockAddr::new_inet(InetAddr::from_std(&address)))
+        .expect("Unable to connect to ourselves.");
+
+    let memory = io_queue.malloc(&mut pair.client);
+    let refused = io_queue.push(&mut pair.client, memory).unwrap_err();
+    assert_eq!(refused.error, IoQueueError::EmptyPush);
+    io_queue.free(&mut pair.client, refused.memory).unwrap();
+    assert_eq!(
+        io_queue.push_copy(&mut pair.client, &[]).unwrap_err(),
+        IoQueueError::EmptyPush
+    );
+
+    let push_qt = io_queue.push_copy(&mut pair.client, &[9]).unwrap();
+    let pop_qt = io_queue.pop(&mut pair.server);
+    match io_queue.wait(pop_qt) {
+        CompletedRequest::Pop(memory, _) => {
+            assert_eq!(memory.as_slice(), [9]);
+            io_queue.free(&mut pair.server, memory).unwrap();
+        }
+        CompletedRequest::EndOfStream => panic!("The empty push half-closed the connection."),
+        _ => panic!("Expected the pushed byte."),
+    }
+    io_queue.wait(push_qt);
+}
+
+/// Push more through a ring than it holds, in pieces that don't divide its size, so writes wrap
+/// around its end and the sender has to wait for the receiver's tail.
+#[test]
+fn ring_wraps_around() {
+    let ip_address = test_address();
+    let address: SocketAddr = format!("{}:{}", ip_address, RING_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+    let payload: Vec<u8> = (0..100).collect();
+    let expected = payload.clone();
+
+    let (listening, is_listening) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+        let mut listening_qd = io_queue.socket();
+        io_queue
+            .bind(
+                &mut listening_qd,
+                &SockAddr::new_inet(InetAddr::from_std(&address)),
+            )
+            .unwrap();
+        io_queue.listen(&mut listening_qd);
+        listening.send(()).unwrap();
+
+        let mut connected_qd = io_queue.accept(&mut listening_qd);
+        let mut ring = io_queue.ring_receiver::<16>(&mut connected_qd).unwrap();
+        let mut received = Vec::new();
+        while received.len() < 100 {
+            match io_queue.ring_read(&mut connected_qd, &mut ring) {
+                Ok(data) => received.extend(data),
+                Err(IoQueueError::WouldBlock) => {}
+                Err(e) => panic!("Ring read failed: {}", e),
+            }
+            io_queue.poll();
+        }
+        received
+    });
+
+    is_listening.recv().unwrap();
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut connection = io_queue.socket();
+    io_queue
+        .connect(&mut connection, &ip_address, RING_PORT)
+        .expect("Unable to connect.");
+    let mut ring = io_queue.ring_sender::<16>(&mut connection).unwrap();
+    assert_eq!(
+        io_queue.ring_write(&mut connection, &mut ring, &[0; 17]),
+        Err(IoQueueError::MessageTooLarge)
+    );
+    for piece in payload.chunks(7) {
+        loop {
+            match io_queue.ring_write(&mut connection, &mut ring, piece) {
+                Ok(()) => break,
+                Err(IoQueueError::WouldBlock) => io_queue.poll(),
+                Err(e) => panic!("Ring write failed: {}", e),
+            }
+        }
+    }
+
+    assert_eq!(server.join().unwrap(), expected);
+}
+
+/// Tear both connections down before their ring ends. The rings keep the protection domain they
+/// are registered with alive, so it is only released once they are gone too.
+#[test]
+fn ring_outlives_connection() {
+    let ip_address = test_address();
+    let address: SocketAddr = format!("{}:{}", ip_address, RING_TEARDOWN_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+
+    let (listening, is_listening) = mpsc::channel();
+    let server = thread::spawn(move || {
+        let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+        let mut listening_qd = io_queue.socket();
+        io_queue
+            .bind(
+                &mut listening_qd,
+                &SockAddr::new_inet(InetAddr::from_std(&address)),
+            )
+            .unwrap();
+        io_queue.listen(&mut listening_qd);
+        listening.send(()).unwrap();
+
+        let mut connected_qd = io_queue.accept(&mut listening_qd);
+        let ring = io_queue.ring_receiver::<16>(&mut connected_qd).unwrap();
+        drop(connected_qd);
+        drop(listening_qd);
+        drop(io_queue);
+        drop(ring);
+    });
+
+    is_listening.recv().unwrap();
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut connection = io_queue.socket();
+    io_queue
+        .connect(&mut connection, &ip_address, RING_TEARDOWN_PORT)
+        .expect("Unable to connect.");
+    let ring = io_queue.ring_sender::<16>(&mut connection).unwrap();
+    io_queue.disconnect(connection);
+    drop(io_queue);
+    drop(ring);
+
+    server.join().unwrap();
+}
+
+#[test]
+fn write_copy_lands_in_remote_region() {
+    let address: SocketAddr = format!("{}:{}", test_address(), WRITE_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::new();
+    let mut pair = io_queue
+        .connected_pair(&SockAddr::new_inet(InetAddr::from_std(&address)))
+        .expect("Unable to connect to ourselves.");
+    let mut target = {
+        let mut pd = unsafe { io_queue.raw_pd(&pair.server) };
+        VolatileRdmaMemory::<u8, 8>::new(&mut pd)
+    };
+    target.write(&[0; 8]);
+    io_queue.register_remote(&mut pair.client, RemoteRegion::of(&mut target));
+    let region = io_queue.remote_regions(&pair.client)[0];
+
+    match io_queue.write_copy(&mut pair.client, &region, 6, &[1, 2, 3]) {
+        Err(IoQueueError::OutOfBounds {
+            requested: 9,
+            available: 8,
+        }) => {}
+        _ => panic!("Expected the write past the end of the region to be refused."),
+    }
+    let qt = io_queue
+        .write_copy(&mut pair.client, &region, 2, &[1, 2, 3])
+        .unwrap();
+    assert!(matches!(io_queue.wait(qt), CompletedRequest::Sent));
+    assert_eq!(target.read(), [0, 0, 1, 2, 3, 0, 0, 0]);
+}
+
+/// A doorbell pops as `Notify` behind the data sent before it, and the header that tells the two
+/// apart never shows up in either side's buffers.
+#[test]
+fn notify_is_not_data() {
+    let address: SocketAddr = format!("{}:{}", test_address(), NOTIFY_PORT)
+        .parse()
+        .expect("Unable to parse socket address");
+    let payload = [4u8, 5, 6];
+
+    let config = IoQueueConfig {
+        notifications: true,
+        ..IoQueueConfig::default()
+    };
+    let mut io_queue = IoQueue::<64, 64, 32, 16, 64>::with_config(config).unwrap();
+    let mut pair = io_queue
+        .connected_pair(&SockAddr::new_inet(InetAddr::from_std(&address)))
+        .expect("Unable to connect to ourselves.");
+
+    let mut memory = io_queue.malloc(&mut pair.client);
+    memory.as_mut_slice(payload.len()).copy_from_slice(&payload);
+    let push_qt = io_queue.push(&mut pair.client, memory).unwrap();
+    let notify_qt = io_queue.notify(&mut pair.client).unwrap();
+
+    let memory = io_queue.wait(push_qt).push_op();
+    assert_eq!(memory.as_slice(), payload);
+    io_queue.free(&mut pair.client, memory).unwrap();
+    assert!(matches!(io_queue.wait(notify_qt), CompletedRequest::Sent));
+
+    let pop_qt = io_queue.pop(&mut pair.server);
+    let memory = io_queue.wait(pop_qt).pop_op();
+    assert_eq!(memory.as_slice(), payload);
+    io_queue.free(&mut pair.server, memory).unwrap();
+    let pop_qt = io_queue.pop(&mut pair.server);
+    assert!(matches!(io_queue.wait(pop_qt), CompletedRequest::Notify));
+}